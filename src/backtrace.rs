@@ -0,0 +1,151 @@
+//! Stack backtraces. The kernel is built with frame pointers, so every frame
+//! starts with the caller's RBP followed by the return address. The walker
+//! follows that chain for as long as it stays inside one of the known
+//! stacks, and names each address from the `.ksyms` table which the second
+//! link of the kernel embeds (see `tools/ksyms.sh`).
+
+use crate::kernel_const::{FRAME_SIZE, STACK_BOTTOM, STACK_TOP};
+use crate::memory::stack_controller;
+use crate::{print, println};
+use core::convert::TryInto;
+use core::str;
+
+const BACKTRACE_MAX_FRAMES: usize = 32;
+const KSYMS_ENTRY_SIZE: usize = 24;
+
+#[cfg(not(test))]
+extern "C" {
+    /// returns the frame pointer of its caller, see `entry.asm`
+    fn read_rbp() -> u64;
+    static __ksyms_start: u8;
+    static __ksyms_end: u8;
+}
+
+/// the RBP of whoever calls this
+#[inline(always)]
+pub fn current_rbp() -> u64 {
+    #[cfg(not(test))]
+    return unsafe { read_rbp() };
+    #[cfg(test)]
+    return 0;
+}
+
+/// The stack `addr` lies on, as `(lowest, highest)` address.
+fn stack_of(addr: u64) -> Option<(u64, u64)> {
+    // the frame below the boot stack is its unmapped guard
+    if addr >= STACK_BOTTOM + FRAME_SIZE && addr < STACK_TOP {
+        return Some((STACK_BOTTOM + FRAME_SIZE, STACK_TOP));
+    }
+    stack_controller::stack_of(addr).map(|s| (s.bottom, s.top))
+}
+
+/// Calls `f` with the return address of every frame from `rbp` on, the
+/// innermost first, until `f` returns false or the chain leaves its stack.
+pub fn walk<F: FnMut(u64) -> bool>(mut rbp: u64, mut f: F) {
+    let (lo, hi) = match stack_of(rbp) {
+        Some(stack) => stack,
+        None => return,
+    };
+    while rbp >= lo && rbp + 16 <= hi && rbp & 7 == 0 {
+        let (next, ret) = unsafe { (*(rbp as *const u64), *((rbp + 8) as *const u64)) };
+        if ret == 0 || !f(ret) {
+            break;
+        }
+        // frames only ever move up the stack
+        if next <= rbp {
+            break;
+        }
+        rbp = next;
+    }
+}
+
+#[cfg(not(test))]
+fn ksyms() -> &'static [u8] {
+    unsafe {
+        let start = &__ksyms_start as *const u8;
+        let end = &__ksyms_end as *const u8;
+        core::slice::from_raw_parts(start, end as usize - start as usize)
+    }
+}
+
+#[cfg(test)]
+fn ksyms() -> &'static [u8] {
+    &[]
+}
+
+fn read_u64(table: &[u8], at: usize) -> u64 {
+    u64::from_le_bytes(table[at..at + 8].try_into().unwrap())
+}
+
+/// The function `addr` belongs to and the offset into it. Empty until the
+/// kernel is linked a second time with its symbol table.
+pub fn symbolize(addr: u64) -> Option<(&'static str, u64)> {
+    let table = ksyms();
+    if table.len() < 8 {
+        return None;
+    }
+    let count = read_u64(table, 0) as usize;
+    let names = 8 + count * KSYMS_ENTRY_SIZE;
+    let entry = |i: usize| 8 + i * KSYMS_ENTRY_SIZE;
+
+    // the last symbol at or below `addr`
+    let (mut lo, mut hi) = (0, count);
+    while lo < hi {
+        let mid = (lo + hi) / 2;
+        if read_u64(table, entry(mid)) <= addr {
+            lo = mid + 1;
+        } else {
+            hi = mid;
+        }
+    }
+    if lo == 0 {
+        return None;
+    }
+
+    let e = entry(lo - 1);
+    let start = read_u64(table, e);
+    let off = names + read_u64(table, e + 8) as usize;
+    let len = read_u64(table, e + 16) as usize;
+    let name = str::from_utf8(table.get(off..off + len)?).ok()?;
+    Some((name, addr - start))
+}
+
+/// prints `addr` and, when it is known, the function it is in
+pub fn print_addr(addr: u64) {
+    match symbolize(addr) {
+        Some((name, off)) => print!("0x{:x} {}+0x{:x}", addr, name, off),
+        None => print!("0x{:x}", addr),
+    }
+}
+
+fn print_frame(i: usize, addr: u64) {
+    print!("  #{:<2} ", i);
+    print_addr(addr);
+    println!();
+}
+
+/// Prints the backtrace of an interrupted context, `rip` is where it stopped
+/// and `rbp` its frame pointer.
+pub fn print(rip: u64, rbp: u64) {
+    println!("backtrace:");
+    print_frame(0, rip);
+    let mut i = 1;
+    walk(rbp, |ret| {
+        // a return address points behind the call
+        print_frame(i, ret - 1);
+        i += 1;
+        i < BACKTRACE_MAX_FRAMES
+    });
+}
+
+/// Prints the backtrace of the caller.
+#[inline(never)]
+pub fn print_current() {
+    println!("backtrace:");
+    let mut i = 0;
+    walk(current_rbp(), |ret| {
+        print_frame(i, ret - 1);
+        i += 1;
+        i < BACKTRACE_MAX_FRAMES
+    });
+}
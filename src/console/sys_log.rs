@@ -1,10 +1,13 @@
 use super::vga_buffer;
 use crate::{warn, println};
+use crate::memory::arena::{ArenaString, LOG_ARENA};
+use crate::memory::oom;
+use crate::time::rtc::{self, DateTime};
 use crate::util::Flag;
-use alloc::{boxed::Box, string::String};
+use alloc::boxed::Box;
 use conquer_once::spin::OnceCell;
 use core::{
-    fmt::{Arguments, Error, Write},
+    fmt::{Arguments, Write},
     pin::Pin,
     task::{Context, Poll},
 };
@@ -63,26 +66,32 @@ impl SysLogLevel {
 #[derive(Debug)]
 #[repr(u64)]
 pub enum ScrnOut {
-    LOG_MSG(LogLevel, String),
-    INPUT_MSG(String),
+    LOG_MSG(LogLevel, ArenaString),
+    INPUT_MSG(ArenaString),
 }
 
 impl ScrnOut {
+    fn size(&self) -> usize {
+        match self {
+            Self::LOG_MSG(_, msg) | Self::INPUT_MSG(msg) => msg.capacity(),
+        }
+    }
+
     fn print(&self) {
         match self {
             Self::LOG_MSG(LogLevel::ERROR, msg) if SYS_LOG_LEVEL.lock().is_on(LogLevel::ERROR) => {
-                vga_buffer::WRITER.lock().error(msg.as_ref())
+                vga_buffer::WRITER.lock().error(msg.as_str())
             }
             Self::LOG_MSG(LogLevel::WARN, msg) if SYS_LOG_LEVEL.lock().is_on(LogLevel::WARN) => {
-                vga_buffer::WRITER.lock().warn(msg.as_ref())
+                vga_buffer::WRITER.lock().warn(msg.as_str())
             }
             Self::LOG_MSG(LogLevel::DEBUG, msg) if SYS_LOG_LEVEL.lock().is_on(LogLevel::DEBUG) => {
-                vga_buffer::WRITER.lock().debug(msg.as_ref())
+                vga_buffer::WRITER.lock().debug(msg.as_str())
             }
             Self::LOG_MSG(LogLevel::INFO, msg) if SYS_LOG_LEVEL.lock().is_on(LogLevel::INFO) => {
-                vga_buffer::WRITER.lock().info(msg.as_ref())
+                vga_buffer::WRITER.lock().info(msg.as_str())
             }
-            Self::INPUT_MSG(msg) => vga_buffer::WRITER.lock().input(msg.as_ref()),
+            Self::INPUT_MSG(msg) => vga_buffer::WRITER.lock().input(msg.as_str()),
             Self::LOG_MSG(_, _) => (),
         }
     }
@@ -120,14 +129,24 @@ impl Stream for LogMsgStream {
     }
 }
 
-fn _fmt(args: Arguments) -> String {
-    fn write<W: Write>(f: &mut W, args: Arguments) -> Result<(), Error> {
-        f.write_fmt(args)
-    };
+/// Messages come out of `LOG_ARENA`, once its quota is used up new messages
+/// are dropped until the queue drains.
+fn _fmt(args: Arguments) -> Option<ArenaString> {
+    let mut buf = ArenaString::new_in(&LOG_ARENA);
+    buf.write_fmt(args).ok()?;
+    Some(buf)
+}
 
-    let mut buf = String::new();
-    write(&mut buf, args).unwrap();
-    buf
+/// Drops the log messages which haven't been printed yet, registered as an
+/// out of memory reclaim callback.
+fn reclaim_log_queue() -> usize {
+    let mut freed = 0;
+    if let Ok(queue) = LOG_MSG_QUEUE.try_get() {
+        while let Ok(msg) = queue.pop() {
+            freed += msg.size();
+        }
+    }
+    freed
 }
 
 fn push_msg(msg: ScrnOut) {
@@ -142,15 +161,32 @@ fn push_msg(msg: ScrnOut) {
     }
 }
 
+/// Log messages start with the UTC time they were logged at, to the
+/// millisecond, when there is wall-clock time.
 pub fn _log(level: LogLevel, args: Arguments) {
     if IS_STARTED.lock().get() {
-        push_msg(ScrnOut::LOG_MSG(level, _fmt(args)));
+        let msg = match rtc::unix_nanos() {
+            Some(ns) => {
+                let t = DateTime::from_unix_seconds(ns / 1_000_000_000);
+                let ms = ns / 1_000_000 % 1000;
+                _fmt(format_args!(
+                    "[{:02}:{:02}:{:02}.{:03}] {}",
+                    t.hour, t.minute, t.second, ms, args
+                ))
+            }
+            None => _fmt(args),
+        };
+        if let Some(msg) = msg {
+            push_msg(ScrnOut::LOG_MSG(level, msg));
+        }
     }
 }
 
 pub fn _input(args: Arguments) {
     if IS_STARTED.lock().get() {
-        push_msg(ScrnOut::INPUT_MSG(_fmt(args)));
+        if let Some(msg) = _fmt(args) {
+            push_msg(ScrnOut::INPUT_MSG(msg));
+        }
     }
 }
 
@@ -160,6 +196,7 @@ pub fn init() {
         .expect("LogMsgStream::new should only the called once");
     IS_STARTED.lock().on();
     SYS_LOG_LEVEL.lock().on(LogLevel::ERROR);
+    oom::register_reclaim(reclaim_log_queue);
 }
 
 pub async fn print_log() {
@@ -19,3 +19,10 @@ start:
     jmp g8start
     ;hlt
 ; reserve space for stack
+
+section .text
+global read_rbp
+; returns the frame pointer of the calling function
+read_rbp:
+    mov rax, rbp
+    ret
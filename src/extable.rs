@@ -0,0 +1,111 @@
+//! Exception fixups. A few functions in `trap.asm` are allowed to fault: the
+//! `.extable` section pairs each of their memory accesses with the address
+//! to resume at, and `trap_dispatch` resumes there on a page fault or a
+//! general protection fault instead of halting. The probes built on them
+//! read and write kernel memory which may not be mapped.
+
+use crate::{print, println};
+
+extern "C" {
+    static __extable_start: ExtableEntry;
+    static __extable_end: ExtableEntry;
+    fn probe_read_u8(addr: u64, out: *mut u8) -> u64;
+    fn probe_read_u64(addr: u64, out: *mut u64) -> u64;
+    fn probe_write_u8(addr: u64, value: u8) -> u64;
+}
+
+const PEEK_DEFAULT_LEN: u64 = 64;
+const PEEK_MAX_LEN: u64 = 4096;
+
+#[repr(C)]
+struct ExtableEntry {
+    /// the instruction which may fault
+    fault: u64,
+    /// where to go on when it does
+    fixup: u64,
+}
+
+/// The probed address faulted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Fault;
+
+fn entries() -> &'static [ExtableEntry] {
+    unsafe {
+        let start = &__extable_start as *const ExtableEntry;
+        let end = &__extable_end as *const ExtableEntry;
+        let n = (end as usize - start as usize) / core::mem::size_of::<ExtableEntry>();
+        core::slice::from_raw_parts(start, n)
+    }
+}
+
+/// where to resume when the instruction at `rip` faults, if it may
+pub fn fixup(rip: u64) -> Option<u64> {
+    entries().iter().find(|e| e.fault == rip).map(|e| e.fixup)
+}
+
+pub fn peek_u8(addr: u64) -> Result<u8, Fault> {
+    let mut value = 0;
+    match unsafe { probe_read_u8(addr, &mut value) } {
+        0 => Ok(value),
+        _ => Err(Fault),
+    }
+}
+
+pub fn peek_u64(addr: u64) -> Result<u64, Fault> {
+    let mut value = 0;
+    match unsafe { probe_read_u64(addr, &mut value) } {
+        0 => Ok(value),
+        _ => Err(Fault),
+    }
+}
+
+/// Writes `value` to `addr` if it is mapped writable. Whatever lives there
+/// is the caller's problem.
+pub unsafe fn poke_u8(addr: u64, value: u8) -> Result<(), Fault> {
+    match probe_write_u8(addr, value) {
+        0 => Ok(()),
+        _ => Err(Fault),
+    }
+}
+
+/// `peek <addr> [len]`: a hex dump of `len` bytes at `addr`, both in hex,
+/// up to the first byte which can't be read.
+pub fn print_peek(args: &str) {
+    let mut args = args.split_whitespace();
+    let parse = |s: &str| u64::from_str_radix(s.trim_start_matches("0x"), 16).ok();
+    let addr = match args.next().and_then(parse) {
+        Some(addr) => addr,
+        None => {
+            println!("usage: peek <addr> [len], in hex");
+            return;
+        }
+    };
+    let len = match args.next() {
+        Some(len) => match parse(len) {
+            Some(len) => core::cmp::min(len, PEEK_MAX_LEN),
+            None => {
+                println!("usage: peek <addr> [len], in hex");
+                return;
+            }
+        },
+        None => PEEK_DEFAULT_LEN,
+    };
+
+    for i in 0..len {
+        let at = addr.wrapping_add(i);
+        if i % 16 == 0 {
+            if i != 0 {
+                println!();
+            }
+            print!("{:016x}:", at);
+        }
+        match peek_u8(at) {
+            Ok(byte) => print!(" {:02x}", byte),
+            Err(Fault) => {
+                println!(" not mapped");
+                return;
+            }
+        }
+    }
+    println!();
+}
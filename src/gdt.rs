@@ -1,3 +1,4 @@
+use crate::memory::stack_controller;
 use lazy_static::lazy_static;
 use x86_64::structures::gdt::SegmentSelector;
 use x86_64::structures::gdt::{Descriptor, GlobalDescriptorTable};
@@ -5,17 +6,29 @@ use x86_64::structures::tss::TaskStateSegment;
 use x86_64::VirtAddr;
 
 pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
+pub const NMI_IST_INDEX: u16 = 1;
+pub const MACHINE_CHECK_IST_INDEX: u16 = 2;
+pub const PAGE_FAULT_IST_INDEX: u16 = 3;
+
+/// The exceptions which switch to a stack of their own, so they can still be
+/// reported when the kernel stack is gone. A page fault inside the page
+/// fault handler starts over at the top of its stack, on top of the outer
+/// fault's frame, so the report checks the page tables before it reads
+/// memory which may not be mapped.
+const IST_STACKS: [(u16, &str, u64); 4] = [
+    (DOUBLE_FAULT_IST_INDEX, "double fault", 64 << 10),
+    (NMI_IST_INDEX, "nmi", 16 << 10),
+    (MACHINE_CHECK_IST_INDEX, "machine check", 16 << 10),
+    (PAGE_FAULT_IST_INDEX, "page fault", 64 << 10),
+];
 
 lazy_static! {
     static ref TSS: TaskStateSegment = {
         let mut tss = TaskStateSegment::new();
-        tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = {
-            const STATCK_SIZE: usize = 4096;
-            static mut STACK: [u8; STATCK_SIZE] = [0; STATCK_SIZE];
-            let stack_start = VirtAddr::from_ptr(unsafe { &STACK });
-            let stack_end = stack_start + STATCK_SIZE;
-            stack_end
-        };
+        for (index, name, size) in IST_STACKS.iter() {
+            let stack = stack_controller::alloc_stack(name, *size).expect("ist stack");
+            tss.interrupt_stack_table[*index as usize] = VirtAddr::new(stack.top);
+        }
         tss
     };
 }
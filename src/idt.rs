@@ -1,43 +1,40 @@
-use crate::hlt_loop;
-use crate::{print, println};
+use crate::irq::{self, apic, IRQ_KEYBOARD, IRQ_LINES, IRQ_TIMER, IRQ_VECTOR_BASE};
+use crate::time;
+use crate::trap;
 use crate::task::sys_task::{add_sys_task, SysTask};
+use crate::{print, println};
 use core::sync::atomic::{AtomicU64, Ordering};
 use lazy_static::lazy_static;
-use pic8259_simple::ChainedPics;
-use spin;
-use x86_64::structures::idt::PageFaultErrorCode;
-use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame};
-
-use crate::gdt;
-use crate::{debug, error, info, warn};
-
-pub const PIC_1_OFFSET: u8 = 32;
-pub const PIC_2_OFFSET: u8 = PIC_1_OFFSET + 8;
-
+use x86_64::structures::idt::InterruptDescriptorTable;
+
+const VECTORS: usize = 256;
+
+/// Counters of one vector, bumped by its handler without taking a lock.
+struct VectorStats {
+    count: AtomicU64,
+    /// interrupts no handler claimed
+    spurious: AtomicU64,
+    /// interrupts of a line without any handler
+    unhandled: AtomicU64,
+    cycles: AtomicU64,
+    max_cycles: AtomicU64,
+}
 
-static TIMMER_COUNT: AtomicU64 = AtomicU64::new(0);
+const VECTOR_STATS_ZERO: VectorStats = VectorStats {
+    count: AtomicU64::new(0),
+    spurious: AtomicU64::new(0),
+    unhandled: AtomicU64::new(0),
+    cycles: AtomicU64::new(0),
+    max_cycles: AtomicU64::new(0),
+};
 
-pub static PICS: spin::Mutex<ChainedPics> =
-    spin::Mutex::new(unsafe { ChainedPics::new(PIC_1_OFFSET, PIC_2_OFFSET) });
+static VECTOR_STATS: [VectorStats; VECTORS] = [VECTOR_STATS_ZERO; VECTORS];
 
 lazy_static! {
     static ref IDT: InterruptDescriptorTable = {
         let mut idt = InterruptDescriptorTable::new();
-        idt.breakpoint.set_handler_fn(breakpoint_handler);
-        idt.overflow.set_handler_fn(overflow_handler);
-        idt.bound_range_exceeded.set_handler_fn(bound_range_exceeded_handler);
-        idt.invalid_opcode.set_handler_fn(invalid_opcode_handler);
-        idt.divide_error.set_handler_fn(divide_error_handler);
-        unsafe {
-            idt.double_fault
-                .set_handler_fn(double_fault_handler)
-                .set_stack_index(0 as u16);
-        }
-        idt.page_fault.set_handler_fn(page_fault_handler);
-        idt.general_protection_fault
-            .set_handler_fn(general_protected_handler);
-        idt[InterruptIndex::Timer.as_usize()].set_handler_fn(timer_interrupt_handler);
-        idt[InterruptIndex::Keyboard.as_usize()].set_handler_fn(keyboard_interrupt_handler);
+        trap::install(&mut idt);
+        irq::install(&mut idt);
         idt
     };
 }
@@ -46,92 +43,70 @@ pub fn init_idt() {
     IDT.load();
 }
 
-#[derive(Debug, Clone, Copy)]
-#[repr(u8)]
-pub enum InterruptIndex {
-    Timer = PIC_1_OFFSET,
-    Keyboard,
-}
-
-impl InterruptIndex {
-    fn as_u8(self) -> u8 {
-        self as u8
-    }
-
-    fn as_usize(self) -> usize {
-        usize::from(self.as_u8())
+/// Counts an interrupt on `vector` whose handlers took `cycles`.
+pub fn record(vector: u8, cycles: u64, spurious: bool) {
+    let stats = &VECTOR_STATS[vector as usize];
+    stats.count.fetch_add(1, Ordering::Relaxed);
+    if spurious {
+        stats.spurious.fetch_add(1, Ordering::Relaxed);
     }
+    stats.cycles.fetch_add(cycles, Ordering::Relaxed);
+    stats.max_cycles.fetch_max(cycles, Ordering::Relaxed);
 }
 
-extern "x86-interrupt" fn invalid_opcode_handler(stack_frame: &mut InterruptStackFrame) {
-    panic!("EXCEPTION: INVALID OPCODE\n{:#?}", stack_frame);
+/// Counts an interrupt on `vector` which had no handler to ask.
+pub fn record_unhandled(vector: u8) {
+    VECTOR_STATS[vector as usize].unhandled.fetch_add(1, Ordering::Relaxed);
 }
 
-extern "x86-interrupt" fn bound_range_exceeded_handler(stack_frame: &mut InterruptStackFrame) {
-    panic!("EXCEPTION: BOUND RANGE EXCEEDED\n{:#?}", stack_frame);
-}
-
-extern "x86-interrupt" fn divide_error_handler(stack_frame: &mut InterruptStackFrame) {
-    panic!("EXCEPTION: DIVIDE BY ZERO\n{:#?}", stack_frame);
-}
-
-extern "x86-interrupt" fn overflow_handler(stack_frame: &mut InterruptStackFrame) {
-    panic!("EXCEPTION: OVER FLOW\n{:#?}", stack_frame);
-}
-
-extern "x86-interrupt" fn breakpoint_handler(stack_frame: &mut InterruptStackFrame) {
-    println!("EXCEPTION: BREAKPOINT\n{:#?}", stack_frame);
+/// The `irq` command, every vector which fired so far.
+pub fn print_stats() {
+    println!("vec        count   spurious  unhandled  avg cycles  max cycles");
+    for (vector, stats) in VECTOR_STATS.iter().enumerate() {
+        let count = stats.count.load(Ordering::Relaxed);
+        if count == 0 {
+            continue;
+        }
+        print!(
+            "{:>3} {:>12} {:>10} {:>10} {:>11} {:>11}  ",
+            vector,
+            count,
+            stats.spurious.load(Ordering::Relaxed),
+            stats.unhandled.load(Ordering::Relaxed),
+            stats.cycles.load(Ordering::Relaxed) / count,
+            stats.max_cycles.load(Ordering::Relaxed)
+        );
+        let line = vector.wrapping_sub(IRQ_VECTOR_BASE as usize);
+        if vector < 32 {
+            let (mnemonic, name) = trap::vector_name(vector as u64);
+            print!("{} {}", mnemonic, name);
+        } else if line < IRQ_LINES {
+            irq::print_line(line as u8);
+        } else if vector == apic::APIC_SPURIOUS_VECTOR as usize {
+            print!("APIC spurious");
+        }
+        println!();
+    }
 }
 
-extern "x86-interrupt" fn double_fault_handler(
-    stack_frame: &mut InterruptStackFrame,
-    _error_code: u64,
-) -> ! {
-    panic!("EXCEPTION: DOUBLE FAULT \n{:#?}", stack_frame);
+/// Registers the timer and keyboard handlers, after `irq::init`.
+pub fn init_irqs() {
+    irq::register_irq(IRQ_TIMER, timer_interrupt).expect("timer irq");
+    irq::register_irq(IRQ_KEYBOARD, keyboard_interrupt).expect("keyboard irq");
 }
 
-extern "x86-interrupt" fn timer_interrupt_handler(_stack_frame: &mut InterruptStackFrame) {
-    let i = TIMMER_COUNT.fetch_add(1, Ordering::Relaxed);
-    
+fn timer_interrupt() -> bool {
+    let i = time::tick();
     add_sys_task(SysTask::TIMMER(i));
-    unsafe {
-        PICS.lock()
-            .notify_end_of_interrupt(InterruptIndex::Timer.as_u8());
-    };
+    true
 }
 
-extern "x86-interrupt" fn keyboard_interrupt_handler(_stack_frame: &mut InterruptStackFrame) {
+fn keyboard_interrupt() -> bool {
     use x86_64::instructions::port::Port;
     let mut port = Port::new(0x60);
     let scancode: u8 = unsafe { port.read() };
     add_sys_task(SysTask::KEY(scancode));
-    unsafe {
-        PICS.lock()
-            .notify_end_of_interrupt(InterruptIndex::Keyboard.as_u8());
-    }
-}
-
-extern "x86-interrupt" fn page_fault_handler(
-    stack_frame: &mut InterruptStackFrame,
-    error_code: PageFaultErrorCode,
-) {
-    use x86_64::registers::control::Cr2;
-
-    println!("EXCEPTION: PAGE FAULT");
-    println!("Accessed Address: {:?}", Cr2::read());
-    println!("Error Code: {:?}", error_code);
-    println!("{:#?}", stack_frame);
-    hlt_loop();
-}
-
-extern "x86-interrupt" fn general_protected_handler(
-    stack_frame: &mut InterruptStackFrame,
-    error_code: u64,
-) {
-    println!("EXCEPTION: GENERAL PROTECT");
-    println!("Error Code: {:?}", error_code);
-    println!("{:#?}", stack_frame);
-    hlt_loop();
+    true
 }
 
 // #[test_case]
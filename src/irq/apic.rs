@@ -0,0 +1,133 @@
+//! The local APIC of the boot CPU: acknowledging interrupts, the spurious
+//! vector and the APIC timer.
+
+use crate::memory::mmio;
+use crate::no_interrupt;
+use crate::time::pit;
+use core::arch::x86_64::__cpuid;
+use core::ptr;
+use core::sync::atomic::{AtomicU64, Ordering};
+use x86_64::registers::model_specific::Msr;
+
+pub const APIC_SPURIOUS_VECTOR: u8 = 0xff;
+
+const IA32_APIC_BASE: u32 = 0x1b;
+const APIC_BASE_ENABLE: u64 = 1 << 11;
+const CPUID_EDX_APIC: u32 = 1 << 9;
+
+const APIC_ID: u64 = 0x20;
+const APIC_TPR: u64 = 0x80;
+const APIC_EOI: u64 = 0xb0;
+const APIC_SVR: u64 = 0xf0;
+const APIC_LVT_TIMER: u64 = 0x320;
+const APIC_LVT_LINT0: u64 = 0x350;
+const APIC_LVT_LINT1: u64 = 0x360;
+const APIC_LVT_ERROR: u64 = 0x370;
+const APIC_TIMER_INITIAL: u64 = 0x380;
+const APIC_TIMER_CURRENT: u64 = 0x390;
+const APIC_TIMER_DIVIDE: u64 = 0x3e0;
+
+const APIC_SVR_ENABLE: u32 = 1 << 8;
+const APIC_LVT_MASKED: u32 = 1 << 16;
+const APIC_LVT_PERIODIC: u32 = 1 << 17;
+
+/// the timer calibration counts down this long on PIT channel 2, 10ms
+const CALIBRATE_COUNT: u16 = (pit::PIT_FREQUENCY / 100) as u16;
+
+/// virtual address of the register page, 0 until `init`
+static APIC_BASE: AtomicU64 = AtomicU64::new(0);
+
+/// Divides the bus clock the timer counts down with.
+#[derive(Debug, Clone, Copy)]
+#[repr(u32)]
+pub enum TimerDivide {
+    By1 = 0b1011,
+    By2 = 0b0000,
+    By4 = 0b0001,
+    By8 = 0b0010,
+    By16 = 0b0011,
+    By32 = 0b1000,
+    By64 = 0b1001,
+    By128 = 0b1010,
+}
+
+pub fn supported() -> bool {
+    unsafe { __cpuid(1).edx & CPUID_EDX_APIC != 0 }
+}
+
+fn read(reg: u64) -> u32 {
+    unsafe { ptr::read_volatile((APIC_BASE.load(Ordering::Relaxed) + reg) as *const u32) }
+}
+
+fn write(reg: u64, value: u32) {
+    unsafe { ptr::write_volatile((APIC_BASE.load(Ordering::Relaxed) + reg) as *mut u32, value) }
+}
+
+/// Maps and enables the local APIC at `phys` with the timer and the local
+/// interrupt pins masked.
+pub fn init(phys: u64) -> bool {
+    let base = match mmio::map(phys, 0x1000) {
+        Ok(base) => base,
+        Err(_) => return false,
+    };
+    APIC_BASE.store(base, Ordering::Relaxed);
+    unsafe {
+        let mut msr = Msr::new(IA32_APIC_BASE);
+        let value = msr.read();
+        msr.write(value | APIC_BASE_ENABLE);
+    }
+
+    write(APIC_LVT_TIMER, APIC_LVT_MASKED);
+    write(APIC_LVT_LINT0, APIC_LVT_MASKED);
+    write(APIC_LVT_LINT1, APIC_LVT_MASKED);
+    write(APIC_LVT_ERROR, APIC_LVT_MASKED);
+    write(APIC_TPR, 0);
+    write(APIC_SVR, APIC_SVR_ENABLE | APIC_SPURIOUS_VECTOR as u32);
+    true
+}
+
+pub fn id() -> u8 {
+    (read(APIC_ID) >> 24) as u8
+}
+
+pub fn end_of_interrupt() {
+    write(APIC_EOI, 0);
+}
+
+/// Lets the timer fire `vector` every `count` ticks of the divided bus
+/// clock, it stays masked until its line is unmasked.
+pub fn set_timer(vector: u8, count: u32, divide: TimerDivide) {
+    let masked = read(APIC_LVT_TIMER) & APIC_LVT_MASKED;
+    write(APIC_TIMER_DIVIDE, divide as u32);
+    write(APIC_LVT_TIMER, masked | APIC_LVT_PERIODIC | vector as u32);
+    write(APIC_TIMER_INITIAL, count);
+}
+
+/// Ticks per second of the timer with `divide`, measured against PIT
+/// channel 2 with the timer masked. `None` if the PIT never counts down.
+pub fn timer_frequency(divide: TimerDivide) -> Option<u64> {
+    write(APIC_TIMER_DIVIDE, divide as u32);
+    write(APIC_LVT_TIMER, APIC_LVT_MASKED);
+    let elapsed = no_interrupt!(|| {
+        pit::start_channel_2(CALIBRATE_COUNT);
+        write(APIC_TIMER_INITIAL, u32::MAX);
+        let done = pit::wait_channel_2();
+        let elapsed = u32::MAX - read(APIC_TIMER_CURRENT);
+        write(APIC_TIMER_INITIAL, 0);
+        if done {
+            Some(elapsed as u64)
+        } else {
+            None
+        }
+    })?;
+    Some(elapsed * pit::PIT_FREQUENCY / CALIBRATE_COUNT as u64)
+}
+
+pub fn mask_timer(masked: bool) {
+    let lvt = read(APIC_LVT_TIMER);
+    if masked {
+        write(APIC_LVT_TIMER, lvt | APIC_LVT_MASKED);
+    } else {
+        write(APIC_LVT_TIMER, lvt & !APIC_LVT_MASKED);
+    }
+}
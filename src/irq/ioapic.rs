@@ -0,0 +1,121 @@
+//! I/O APICs. Each input, a global system interrupt (GSI), has a
+//! redirection entry saying which vector it raises on which CPU, its
+//! trigger mode and polarity, and whether it is masked.
+
+use super::madt::{Madt, MADT_MAX_IOAPICS};
+use super::{Polarity, Trigger};
+use crate::memory::mmio;
+use crate::util::IrqLocked;
+use core::ptr;
+
+const IOAPIC_REGSEL: u64 = 0x00;
+const IOAPIC_WIN: u64 = 0x10;
+const IOAPIC_VER: u32 = 0x01;
+const IOAPIC_REDTBL: u32 = 0x10;
+
+const REDTBL_ACTIVE_LOW: u64 = 1 << 13;
+const REDTBL_LEVEL: u64 = 1 << 15;
+const REDTBL_MASKED: u64 = 1 << 16;
+
+#[derive(Clone, Copy)]
+struct IoApic {
+    base: u64,
+    gsi_base: u32,
+    inputs: u32,
+}
+
+impl IoApic {
+    fn read(&self, reg: u32) -> u32 {
+        unsafe {
+            ptr::write_volatile((self.base + IOAPIC_REGSEL) as *mut u32, reg);
+            ptr::read_volatile((self.base + IOAPIC_WIN) as *const u32)
+        }
+    }
+
+    fn write(&self, reg: u32, value: u32) {
+        unsafe {
+            ptr::write_volatile((self.base + IOAPIC_REGSEL) as *mut u32, reg);
+            ptr::write_volatile((self.base + IOAPIC_WIN) as *mut u32, value);
+        }
+    }
+
+    fn read_entry(&self, input: u32) -> u64 {
+        let reg = IOAPIC_REDTBL + input * 2;
+        self.read(reg) as u64 | (self.read(reg + 1) as u64) << 32
+    }
+
+    fn write_entry(&self, input: u32, entry: u64) {
+        let reg = IOAPIC_REDTBL + input * 2;
+        // masked while it is half written
+        self.write(reg, REDTBL_MASKED as u32);
+        self.write(reg + 1, (entry >> 32) as u32);
+        self.write(reg, entry as u32);
+    }
+}
+
+static IOAPICS: IrqLocked<[Option<IoApic>; MADT_MAX_IOAPICS]> =
+    IrqLocked::new([None; MADT_MAX_IOAPICS]);
+
+/// Maps the I/O APICs of `madt` with all their inputs masked, returns how
+/// many there are.
+pub fn init(madt: &Madt) -> usize {
+    let mut ioapics = IOAPICS.lock();
+    let mut n = 0;
+    for entry in madt.ioapics.iter().flatten() {
+        let base = match mmio::map(entry.addr, 0x1000) {
+            Ok(base) => base,
+            Err(_) => continue,
+        };
+        let mut ioapic = IoApic {
+            base,
+            gsi_base: entry.gsi_base,
+            inputs: 0,
+        };
+        ioapic.inputs = ((ioapic.read(IOAPIC_VER) >> 16) & 0xff) + 1;
+        for input in 0..ioapic.inputs {
+            ioapic.write_entry(input, REDTBL_MASKED);
+        }
+        ioapics[n] = Some(ioapic);
+        n += 1;
+    }
+    n
+}
+
+/// the I/O APIC with input `gsi` and the number of the input on it
+fn find(ioapics: &[Option<IoApic>], gsi: u32) -> Option<(IoApic, u32)> {
+    ioapics
+        .iter()
+        .flatten()
+        .find(|a| gsi >= a.gsi_base && gsi < a.gsi_base + a.inputs)
+        .map(|a| (*a, gsi - a.gsi_base))
+}
+
+/// Points `gsi` at `vector` on the CPU with APIC id `dest`, masked.
+pub fn route(gsi: u32, vector: u8, trigger: Trigger, polarity: Polarity, dest: u8) -> bool {
+    let ioapics = IOAPICS.lock();
+    let (ioapic, input) = match find(&*ioapics, gsi) {
+        Some(found) => found,
+        None => return false,
+    };
+    let mut entry = vector as u64 | REDTBL_MASKED | (dest as u64) << 56;
+    if trigger == Trigger::Level {
+        entry |= REDTBL_LEVEL;
+    }
+    if polarity == Polarity::Low {
+        entry |= REDTBL_ACTIVE_LOW;
+    }
+    ioapic.write_entry(input, entry);
+    true
+}
+
+pub fn set_masked(gsi: u32, masked: bool) {
+    let ioapics = IOAPICS.lock();
+    if let Some((ioapic, input)) = find(&*ioapics, gsi) {
+        let entry = ioapic.read_entry(input);
+        if masked {
+            ioapic.write_entry(input, entry | REDTBL_MASKED);
+        } else {
+            ioapic.write_entry(input, entry & !REDTBL_MASKED);
+        }
+    }
+}
@@ -0,0 +1,163 @@
+//! Finds the ACPI MADT, which lists the local APIC, the I/O APICs and how
+//! the ISA IRQs are wired to their inputs. The RSDP is searched for in the
+//! identity mapped BIOS area, the tables themselves are mapped through
+//! `mmio`.
+
+use crate::memory::mmio;
+use core::ptr;
+
+pub const MADT_MAX_IOAPICS: usize = 4;
+pub const MADT_MAX_OVERRIDES: usize = 16;
+
+const EBDA_SEGMENT_PTR: u64 = 0x40e;
+const BIOS_AREA_START: u64 = 0xe0000;
+const BIOS_AREA_END: u64 = 0x100000;
+const SDT_HEADER_SIZE: u64 = 36;
+const MADT_PCAT_COMPAT: u32 = 1;
+
+#[derive(Debug, Clone, Copy)]
+pub struct IoApicEntry {
+    pub addr: u64,
+    pub gsi_base: u32,
+}
+
+/// An ISA IRQ wired to another global system interrupt, or with another
+/// trigger mode or polarity than ISA's edge triggered active high.
+#[derive(Debug, Clone, Copy)]
+pub struct Override {
+    pub source: u8,
+    pub gsi: u32,
+    /// MPS INTI flags, bits 0-1 polarity and 2-3 trigger mode
+    pub flags: u16,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Madt {
+    pub lapic_addr: u64,
+    /// whether the machine also has the 8259 PICs
+    pub has_pic: bool,
+    pub ioapics: [Option<IoApicEntry>; MADT_MAX_IOAPICS],
+    pub overrides: [Option<Override>; MADT_MAX_OVERRIDES],
+}
+
+unsafe fn read<T: Copy>(addr: u64) -> T {
+    ptr::read_unaligned(addr as *const T)
+}
+
+unsafe fn checksum_ok(addr: u64, len: u64) -> bool {
+    (0..len).fold(0u8, |sum, i| sum.wrapping_add(read::<u8>(addr + i))) == 0
+}
+
+unsafe fn find_rsdp_in(start: u64, end: u64) -> Option<u64> {
+    (start..end)
+        .step_by(16)
+        .find(|addr| read::<[u8; 8]>(*addr) == *b"RSD PTR " && checksum_ok(*addr, 20))
+}
+
+unsafe fn find_rsdp() -> Option<u64> {
+    let ebda = (read::<u16>(EBDA_SEGMENT_PTR) as u64) << 4;
+    if ebda != 0 {
+        if let Some(rsdp) = find_rsdp_in(ebda, ebda + 1024) {
+            return Some(rsdp);
+        }
+    }
+    find_rsdp_in(BIOS_AREA_START, BIOS_AREA_END)
+}
+
+/// maps the table at `phys` and checks it, returns its virtual address and
+/// length
+unsafe fn map_table(phys: u64) -> Option<(u64, u64)> {
+    let header = mmio::map(phys, SDT_HEADER_SIZE).ok()?;
+    let len = read::<u32>(header + 4) as u64;
+    if len < SDT_HEADER_SIZE {
+        return None;
+    }
+    let table = mmio::map(phys, len).ok()?;
+    if !checksum_ok(table, len) {
+        return None;
+    }
+    Some((table, len))
+}
+
+/// the table with signature `sig` in the RSDT or XSDT
+unsafe fn find_table(sig: &[u8; 4]) -> Option<(u64, u64)> {
+    let rsdp = find_rsdp()?;
+    let revision = read::<u8>(rsdp + 15);
+    let (root, entry_size) = if revision >= 2 && read::<u64>(rsdp + 24) != 0 {
+        (read::<u64>(rsdp + 24), 8)
+    } else {
+        (read::<u32>(rsdp + 16) as u64, 4)
+    };
+
+    let (root, len) = map_table(root)?;
+    let entries = (len - SDT_HEADER_SIZE) / entry_size;
+    for i in 0..entries {
+        let at = root + SDT_HEADER_SIZE + i * entry_size;
+        let phys = if entry_size == 8 {
+            read::<u64>(at)
+        } else {
+            read::<u32>(at) as u64
+        };
+        let header = match mmio::map(phys, SDT_HEADER_SIZE) {
+            Ok(header) => header,
+            Err(_) => continue,
+        };
+        if read::<[u8; 4]>(header) == *sig {
+            return map_table(phys);
+        }
+    }
+    None
+}
+
+impl Madt {
+    /// `None` when there are no ACPI tables or they have no MADT
+    pub fn find() -> Option<Madt> {
+        unsafe {
+            let (table, len) = find_table(b"APIC")?;
+            let mut madt = Madt {
+                lapic_addr: read::<u32>(table + SDT_HEADER_SIZE) as u64,
+                has_pic: read::<u32>(table + SDT_HEADER_SIZE + 4) & MADT_PCAT_COMPAT != 0,
+                ioapics: [None; MADT_MAX_IOAPICS],
+                overrides: [None; MADT_MAX_OVERRIDES],
+            };
+
+            let mut at = table + SDT_HEADER_SIZE + 8;
+            while at + 2 <= table + len {
+                let (kind, entry_len) = (read::<u8>(at), read::<u8>(at + 1) as u64);
+                if entry_len < 2 || at + entry_len > table + len {
+                    break;
+                }
+                match kind {
+                    1 => {
+                        let entry = IoApicEntry {
+                            addr: read::<u32>(at + 4) as u64,
+                            gsi_base: read(at + 8),
+                        };
+                        if let Some(slot) = madt.ioapics.iter_mut().find(|e| e.is_none()) {
+                            *slot = Some(entry);
+                        }
+                    }
+                    2 => {
+                        let entry = Override {
+                            source: read(at + 3),
+                            gsi: read(at + 4),
+                            flags: read(at + 8),
+                        };
+                        if let Some(slot) = madt.overrides.iter_mut().find(|e| e.is_none()) {
+                            *slot = Some(entry);
+                        }
+                    }
+                    5 => madt.lapic_addr = read(at + 4),
+                    _ => {}
+                }
+                at += entry_len;
+            }
+            Some(madt)
+        }
+    }
+
+    /// the override for ISA IRQ `source`
+    pub fn isa_override(&self, source: u8) -> Option<Override> {
+        self.overrides.iter().flatten().find(|o| o.source == source).copied()
+    }
+}
@@ -0,0 +1,403 @@
+//! Device interrupts. Every legacy IRQ line gets a generic stub in the IDT
+//! which runs the handlers registered for the line and acknowledges it, so
+//! a driver only has to call `register_irq`:
+//!
+//! ```ignore
+//! irq::register_irq(irq::IRQ_KEYBOARD, keyboard_interrupt)?;
+//! ```
+//!
+//! A line may be shared by up to `IRQ_SHARE_MAX` handlers. It is unmasked
+//! when its first handler is registered and masked again when its last one
+//! goes away. Tasks await the next interrupt of a line with `wait`.
+//!
+//! Interrupts are routed through the local APIC and the I/O APICs when the
+//! CPU has an APIC and ACPI describes them, through the 8259 PICs
+//! otherwise. With the PICs only the 16 ISA lines exist. With the APICs
+//! line n is global system interrupt n, except that the ISA lines follow
+//! the overrides of the MADT, and `IRQ_APIC_TIMER` is the local APIC timer,
+//! calibrated to fire `TICK_HZ` times a second.
+
+pub mod apic;
+pub mod ioapic;
+pub mod madt;
+pub mod pic;
+mod wait;
+
+use crate::backtrace;
+use crate::idt;
+use crate::time::{tsc, TICK_HZ};
+use crate::util::IrqLocked;
+use crate::{print, println, warn};
+use apic::TimerDivide;
+use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use madt::Madt;
+pub use wait::{wait, IrqWait};
+use x86_64::structures::idt::{HandlerFunc, InterruptDescriptorTable, InterruptStackFrame};
+
+pub const IRQ_LINES: usize = 32;
+pub const IRQ_ISA_LINES: usize = 16;
+pub const IRQ_SHARE_MAX: usize = 4;
+/// line n raises vector `IRQ_VECTOR_BASE + n`
+pub const IRQ_VECTOR_BASE: u8 = pic::PIC_1_OFFSET;
+
+pub const IRQ_TIMER: u8 = 0;
+pub const IRQ_KEYBOARD: u8 = 1;
+pub const IRQ_APIC_TIMER: u8 = IRQ_LINES as u8 - 1;
+
+/// Called with interrupts disabled, returns whether its device raised the
+/// interrupt, a shared line asks every handler in turn.
+pub type IrqHandler = fn() -> bool;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IrqError {
+    /// no such line
+    BadLine,
+    /// all `IRQ_SHARE_MAX` handlers of the line are taken
+    Busy,
+    /// the handler was already registered for the line
+    Registered,
+    /// the handler is not registered for the line
+    NotRegistered,
+    /// the interrupt controller can't do what was asked
+    Unsupported,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Controller {
+    Pic,
+    Apic,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Trigger {
+    Edge,
+    Level,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Polarity {
+    High,
+    Low,
+}
+
+/// where a line comes in on the I/O APICs
+#[derive(Debug, Clone, Copy)]
+struct Route {
+    gsi: u32,
+    trigger: Trigger,
+    polarity: Polarity,
+}
+
+type Chain = [Option<IrqHandler>; IRQ_SHARE_MAX];
+
+static HANDLERS: IrqLocked<[Chain; IRQ_LINES]> = IrqLocked::new([[None; IRQ_SHARE_MAX]; IRQ_LINES]);
+static ROUTES: IrqLocked<[Option<Route>; IRQ_LINES]> = IrqLocked::new([None; IRQ_LINES]);
+static APIC_ENABLED: AtomicBool = AtomicBool::new(false);
+/// whether the local APIC timer got calibrated, `IRQ_APIC_TIMER` exists then
+static APIC_TIMER: AtomicBool = AtomicBool::new(false);
+
+const APIC_TIMER_DIVIDE: TimerDivide = TimerDivide::By16;
+
+/// Sets up the interrupt controllers with every line masked.
+pub fn init() {
+    pic::init();
+    if !apic::supported() {
+        println!("irq: no APIC, using the 8259 PICs");
+        return;
+    }
+    let madt = match Madt::find() {
+        Some(madt) => madt,
+        None => {
+            println!("irq: no MADT, using the 8259 PICs");
+            return;
+        }
+    };
+    if !apic::init(madt.lapic_addr) {
+        println!("irq: can't map the local APIC, using the 8259 PICs");
+        return;
+    }
+    // the PICs' output comes in on LINT0, which `apic::init` masked, not
+    // even their spurious interrupts get through
+    if madt.has_pic {
+        pic::disable();
+    }
+    let n = ioapic::init(&madt);
+    init_routes(&madt);
+    APIC_ENABLED.store(true, Ordering::Relaxed);
+    println!("irq: local APIC {} with {} I/O APICs", apic::id(), n);
+    init_apic_timer();
+}
+
+/// Lets the local APIC timer fire `IRQ_APIC_TIMER` `TICK_HZ` times a second,
+/// masked until a handler is registered.
+fn init_apic_timer() {
+    let hz = match apic::timer_frequency(APIC_TIMER_DIVIDE) {
+        Some(hz) if hz >= TICK_HZ => hz,
+        _ => {
+            println!("irq: can't calibrate the APIC timer, no IRQ_APIC_TIMER");
+            return;
+        }
+    };
+    let count = (hz / TICK_HZ).min(u32::MAX as u64) as u32;
+    apic::set_timer(IRQ_VECTOR_BASE + IRQ_APIC_TIMER, count, APIC_TIMER_DIVIDE);
+    APIC_TIMER.store(true, Ordering::Relaxed);
+    println!("irq: APIC timer at {} kHz, {} Hz", hz / 1000, TICK_HZ);
+}
+
+/// Routes every line to its vector on this CPU, masked.
+fn init_routes(madt: &Madt) {
+    let mut routes = ROUTES.lock();
+    for line in 0..IRQ_APIC_TIMER {
+        let route = if (line as usize) < IRQ_ISA_LINES {
+            isa_route(madt, line)
+        } else {
+            Some(Route {
+                gsi: line as u32,
+                trigger: Trigger::Level,
+                polarity: Polarity::Low,
+            })
+        };
+        routes[line as usize] = route.filter(|r| {
+            ioapic::route(r.gsi, IRQ_VECTOR_BASE + line, r.trigger, r.polarity, apic::id())
+        });
+    }
+}
+
+/// ISA interrupts are edge triggered and active high unless the MADT says
+/// otherwise. `None` when another ISA IRQ took over the input of `line`.
+fn isa_route(madt: &Madt, line: u8) -> Option<Route> {
+    let mut route = Route {
+        gsi: line as u32,
+        trigger: Trigger::Edge,
+        polarity: Polarity::High,
+    };
+    match madt.isa_override(line) {
+        Some(o) => {
+            route.gsi = o.gsi;
+            if o.flags & 0b11 == 0b11 {
+                route.polarity = Polarity::Low;
+            }
+            if (o.flags >> 2) & 0b11 == 0b11 {
+                route.trigger = Trigger::Level;
+            }
+        }
+        None => {
+            let taken = madt.overrides.iter().flatten().any(|o| o.gsi == line as u32);
+            if taken {
+                return None;
+            }
+        }
+    }
+    Some(route)
+}
+
+pub fn controller() -> Controller {
+    if APIC_ENABLED.load(Ordering::Relaxed) {
+        Controller::Apic
+    } else {
+        Controller::Pic
+    }
+}
+
+fn has_line(line: u8) -> bool {
+    match controller() {
+        Controller::Pic => (line as usize) < IRQ_ISA_LINES,
+        Controller::Apic => {
+            if line == IRQ_APIC_TIMER {
+                APIC_TIMER.load(Ordering::Relaxed)
+            } else {
+                (line as usize) < IRQ_LINES && ROUTES.lock()[line as usize].is_some()
+            }
+        }
+    }
+}
+
+fn mask(line: u8) {
+    match controller() {
+        Controller::Pic => pic::mask(line),
+        Controller::Apic if line == IRQ_APIC_TIMER => apic::mask_timer(true),
+        Controller::Apic => {
+            if let Some(route) = ROUTES.lock()[line as usize] {
+                ioapic::set_masked(route.gsi, true);
+            }
+        }
+    }
+}
+
+fn unmask(line: u8) {
+    match controller() {
+        Controller::Pic => pic::unmask(line),
+        Controller::Apic if line == IRQ_APIC_TIMER => apic::mask_timer(false),
+        Controller::Apic => {
+            if let Some(route) = ROUTES.lock()[line as usize] {
+                ioapic::set_masked(route.gsi, false);
+            }
+        }
+    }
+}
+
+fn end_of_interrupt(line: u8) {
+    match controller() {
+        Controller::Pic => pic::end_of_interrupt(line),
+        Controller::Apic => apic::end_of_interrupt(),
+    }
+}
+
+/// Changes the trigger mode and polarity of `line`. The PICs only do edge
+/// triggered, active high.
+pub fn configure(line: u8, trigger: Trigger, polarity: Polarity) -> Result<(), IrqError> {
+    if !has_line(line) {
+        return Err(IrqError::BadLine);
+    }
+    if controller() == Controller::Pic || line == IRQ_APIC_TIMER {
+        return match (trigger, polarity) {
+            (Trigger::Edge, Polarity::High) => Ok(()),
+            _ => Err(IrqError::Unsupported),
+        };
+    }
+
+    let handlers = HANDLERS.lock();
+    let mut routes = ROUTES.lock();
+    let route = routes[line as usize].as_mut().ok_or(IrqError::BadLine)?;
+    route.trigger = trigger;
+    route.polarity = polarity;
+    ioapic::route(route.gsi, IRQ_VECTOR_BASE + line, trigger, polarity, apic::id());
+    if handlers[line as usize].iter().any(|h| h.is_some()) {
+        ioapic::set_masked(route.gsi, false);
+    }
+    Ok(())
+}
+
+/// Adds `handler` to the chain of `line` and unmasks the line.
+pub fn register_irq(line: u8, handler: IrqHandler) -> Result<(), IrqError> {
+    if !has_line(line) {
+        return Err(IrqError::BadLine);
+    }
+    let mut handlers = HANDLERS.lock();
+    let chain = &mut handlers[line as usize];
+    if chain.iter().any(|h| *h == Some(handler)) {
+        return Err(IrqError::Registered);
+    }
+    let slot = chain.iter_mut().find(|h| h.is_none()).ok_or(IrqError::Busy)?;
+    *slot = Some(handler);
+    unmask(line);
+    Ok(())
+}
+
+/// Removes `handler` from the chain of `line`, masking the line when no
+/// handler is left.
+pub fn unregister_irq(line: u8, handler: IrqHandler) -> Result<(), IrqError> {
+    if !has_line(line) {
+        return Err(IrqError::BadLine);
+    }
+    let mut handlers = HANDLERS.lock();
+    let chain = &mut handlers[line as usize];
+    let slot = chain
+        .iter_mut()
+        .find(|h| **h == Some(handler))
+        .ok_or(IrqError::NotRegistered)?;
+    *slot = None;
+    if chain.iter().all(|h| h.is_none()) {
+        mask(line);
+    }
+    Ok(())
+}
+
+/// lines which fired without a handler, a bit per line
+static UNEXPECTED: AtomicU32 = AtomicU32::new(0);
+/// the lines of `UNEXPECTED` which `report_unexpected` hasn't logged yet
+static UNEXPECTED_NEW: AtomicU32 = AtomicU32::new(0);
+
+/// Logs every line which fired without a handler for the first time since
+/// the last call. Called from task context, the interrupt only sets a bit.
+pub fn report_unexpected() {
+    let new = UNEXPECTED_NEW.swap(0, Ordering::Relaxed);
+    for line in (0..IRQ_LINES).filter(|line| new & (1 << line) != 0) {
+        warn!("irq: line {} fired without a handler", line);
+    }
+}
+
+fn dispatch(line: u8) {
+    let start = tsc::cycles();
+    // with the APICs LINT0 is masked, the PICs can't raise any
+    if controller() == Controller::Pic && pic::is_spurious(line) {
+        pic::end_of_spurious(line);
+        idt::record(IRQ_VECTOR_BASE + line, 0, true);
+        return;
+    }
+
+    // a copy, so a handler may register or unregister without dead locking
+    let chain = HANDLERS.lock()[line as usize];
+    let mut handled = false;
+    for handler in chain.iter().flatten() {
+        handled |= handler();
+    }
+    // without handlers it is unhandled, not spurious
+    let has_handlers = chain.iter().any(Option::is_some);
+    if !has_handlers {
+        idt::record_unhandled(IRQ_VECTOR_BASE + line);
+        if UNEXPECTED.fetch_or(1 << line, Ordering::Relaxed) & (1 << line) == 0 {
+            UNEXPECTED_NEW.fetch_or(1 << line, Ordering::Relaxed);
+        }
+    }
+    end_of_interrupt(line);
+    wait::notify(line);
+    idt::record(IRQ_VECTOR_BASE + line, tsc::cycles() - start, !handled && has_handlers);
+}
+
+macro_rules! irq_stubs {
+    ($($line:literal => $stub:ident),* $(,)?) => {
+        $(
+            extern "x86-interrupt" fn $stub(_stack_frame: &mut InterruptStackFrame) {
+                dispatch($line);
+            }
+        )*
+
+        const IRQ_STUBS: [HandlerFunc; IRQ_LINES] = [$($stub),*];
+    };
+}
+
+irq_stubs! {
+    0 => irq_stub_0, 1 => irq_stub_1, 2 => irq_stub_2, 3 => irq_stub_3,
+    4 => irq_stub_4, 5 => irq_stub_5, 6 => irq_stub_6, 7 => irq_stub_7,
+    8 => irq_stub_8, 9 => irq_stub_9, 10 => irq_stub_10, 11 => irq_stub_11,
+    12 => irq_stub_12, 13 => irq_stub_13, 14 => irq_stub_14, 15 => irq_stub_15,
+    16 => irq_stub_16, 17 => irq_stub_17, 18 => irq_stub_18, 19 => irq_stub_19,
+    20 => irq_stub_20, 21 => irq_stub_21, 22 => irq_stub_22, 23 => irq_stub_23,
+    24 => irq_stub_24, 25 => irq_stub_25, 26 => irq_stub_26, 27 => irq_stub_27,
+    28 => irq_stub_28, 29 => irq_stub_29, 30 => irq_stub_30, 31 => irq_stub_31,
+}
+
+/// the local APIC expects no EOI for its spurious vector
+extern "x86-interrupt" fn apic_spurious(_stack_frame: &mut InterruptStackFrame) {
+    idt::record(apic::APIC_SPURIOUS_VECTOR, 0, true);
+}
+
+/// Describes `line` for the `irq` command: the controller, the input and
+/// trigger mode, and the handlers.
+pub fn print_line(line: u8) {
+    match controller() {
+        Controller::Pic => print!("PIC     {:>2}-edge ", line),
+        Controller::Apic if line == IRQ_APIC_TIMER => print!("APIC       timer"),
+        Controller::Apic => match ROUTES.lock()[line as usize] {
+            Some(r) if r.trigger == Trigger::Level => print!("IO-APIC {:>2}-level", r.gsi),
+            Some(r) => print!("IO-APIC {:>2}-edge ", r.gsi),
+            None => print!("IO-APIC    none "),
+        },
+    }
+    let chain = HANDLERS.lock()[line as usize];
+    for handler in chain.iter().flatten() {
+        match backtrace::symbolize(*handler as u64) {
+            Some((name, _)) => print!(" {}", name),
+            None => print!(" 0x{:x}", *handler as u64),
+        }
+    }
+}
+
+/// Points the vectors of all lines at their stubs.
+pub fn install(idt: &mut InterruptDescriptorTable) {
+    for (line, stub) in IRQ_STUBS.iter().enumerate() {
+        idt[IRQ_VECTOR_BASE as usize + line].set_handler_fn(*stub);
+    }
+    idt[apic::APIC_SPURIOUS_VECTOR as usize].set_handler_fn(apic_spurious);
+}
@@ -0,0 +1,90 @@
+//! The two cascaded 8259 PICs. `pic8259_simple` remaps and acknowledges
+//! them, the line masks are written here directly.
+
+use crate::util::IrqLocked;
+use pic8259_simple::ChainedPics;
+use x86_64::instructions::port::Port;
+
+pub const PIC_1_OFFSET: u8 = 32;
+pub const PIC_2_OFFSET: u8 = PIC_1_OFFSET + 8;
+
+const PIC_1_COMMAND: u16 = 0x20;
+const PIC_1_DATA: u16 = 0x21;
+const PIC_2_COMMAND: u16 = 0xa0;
+const PIC_2_DATA: u16 = 0xa1;
+const PIC_CASCADE_LINE: u8 = 2;
+const PIC_READ_ISR: u8 = 0x0b;
+
+static PICS: IrqLocked<ChainedPics> =
+    IrqLocked::new(unsafe { ChainedPics::new(PIC_1_OFFSET, PIC_2_OFFSET) });
+
+/// line masks of both PICs, PIC 2 in the high byte, a set bit masks a line
+static MASKS: IrqLocked<u16> = IrqLocked::new(0xffff);
+
+/// Remaps both PICs behind the exceptions with every line masked except the
+/// cascade, lines are unmasked as handlers are registered.
+pub fn init() {
+    let mut masks = MASKS.lock();
+    *masks = !(1 << PIC_CASCADE_LINE);
+    unsafe {
+        PICS.lock().initialize();
+        write_masks(*masks);
+    }
+}
+
+/// Masks every line, for when the APICs take over.
+pub fn disable() {
+    let mut masks = MASKS.lock();
+    *masks = 0xffff;
+    unsafe { write_masks(*masks) };
+}
+
+unsafe fn write_masks(masks: u16) {
+    Port::<u8>::new(PIC_1_DATA).write(masks as u8);
+    Port::<u8>::new(PIC_2_DATA).write((masks >> 8) as u8);
+}
+
+pub fn mask(line: u8) {
+    let mut masks = MASKS.lock();
+    *masks |= 1 << line;
+    unsafe { write_masks(*masks) };
+}
+
+pub fn unmask(line: u8) {
+    let mut masks = MASKS.lock();
+    *masks &= !(1 << line);
+    unsafe { write_masks(*masks) };
+}
+
+pub fn end_of_interrupt(line: u8) {
+    unsafe { PICS.lock().notify_end_of_interrupt(PIC_1_OFFSET + line) };
+}
+
+/// the in-service registers of both PICs, PIC 2 in the high byte
+fn read_isr() -> u16 {
+    unsafe {
+        Port::<u8>::new(PIC_1_COMMAND).write(PIC_READ_ISR);
+        Port::<u8>::new(PIC_2_COMMAND).write(PIC_READ_ISR);
+        let isr1 = Port::<u8>::new(PIC_1_COMMAND).read();
+        let isr2 = Port::<u8>::new(PIC_2_COMMAND).read();
+        (isr2 as u16) << 8 | isr1 as u16
+    }
+}
+
+/// A PIC raises its lowest priority line, 7 or 15, when the line which
+/// asked for the interrupt went away before the CPU acknowledged it. Such an
+/// interrupt is not in service.
+pub fn is_spurious(line: u8) -> bool {
+    match line {
+        7 | 15 => read_isr() & (1 << line) == 0,
+        _ => false,
+    }
+}
+
+/// Finishes a spurious interrupt. PIC 1 did raise the cascade line for a
+/// spurious one of PIC 2, only PIC 1 gets an EOI.
+pub fn end_of_spurious(line: u8) {
+    if line >= 8 {
+        end_of_interrupt(PIC_CASCADE_LINE);
+    }
+}
@@ -0,0 +1,127 @@
+//! Futures which complete on the next interrupt of a line, so a driver task
+//! can wait for its device inside the executor:
+//!
+//! ```ignore
+//! irq::register_irq(line, quiet_device)?;
+//! loop {
+//!     irq::wait(line).await;
+//!     // read what the device has
+//! }
+//! ```
+//!
+//! The handler registered for the line still has to quiet the device, the
+//! task runs later with interrupts enabled.
+
+use super::IRQ_LINES;
+use core::future::Future;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::task::{Context, Poll};
+use futures_util::task::AtomicWaker;
+
+const NO_EVENTS: AtomicU64 = AtomicU64::new(0);
+const NO_WAKER: AtomicWaker = AtomicWaker::new();
+
+/// interrupts per line so far
+static EVENTS: [AtomicU64; IRQ_LINES] = [NO_EVENTS; IRQ_LINES];
+static WAKERS: [AtomicWaker; IRQ_LINES] = [NO_WAKER; IRQ_LINES];
+
+/// Counts an interrupt of `line` and wakes the task waiting for it, called
+/// after the handlers ran.
+pub(super) fn notify(line: u8) {
+    EVENTS[line as usize].fetch_add(1, Ordering::Release);
+    WAKERS[line as usize].wake();
+}
+
+/// Completes with the number of interrupts `line` had so far, once it had
+/// one more than when `wait` was called. One task per line may wait.
+pub fn wait(line: u8) -> IrqWait {
+    IrqWait {
+        line,
+        seen: EVENTS[line as usize].load(Ordering::Acquire),
+    }
+}
+
+pub struct IrqWait {
+    line: u8,
+    seen: u64,
+}
+
+impl Future for IrqWait {
+    type Output = u64;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<u64> {
+        let line = self.line as usize;
+        let events = EVENTS[line].load(Ordering::Acquire);
+        if events != self.seen {
+            return Poll::Ready(events);
+        }
+
+        WAKERS[line].register(cx.waker());
+        // it may have come in before the waker was there
+        let events = EVENTS[line].load(Ordering::Acquire);
+        if events != self.seen {
+            Poll::Ready(events)
+        } else {
+            Poll::Pending
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::task::executor::Executor;
+    use alloc::sync::Arc;
+
+    /// spawns a task which waits for `wait` and stores what it completed with
+    fn spawn_waiter(executor: &mut Executor, wait: IrqWait) -> Arc<AtomicU64> {
+        let done = Arc::new(AtomicU64::new(0));
+        let d = done.clone();
+        executor
+            .try_spawn(async move { d.store(wait.await, Ordering::Relaxed) })
+            .unwrap();
+        done
+    }
+
+    // every test waits on a line of its own, nothing else notifies them
+
+    #[test]
+    fn notify_wakes_the_waiting_task() {
+        let line = 20;
+        let before = EVENTS[line as usize].load(Ordering::Relaxed);
+        let mut executor = Executor::new();
+        let done = spawn_waiter(&mut executor, wait(line));
+
+        executor.run_ready_tasks();
+        executor.run_ready_tasks();
+        assert_eq!(done.load(Ordering::Relaxed), 0);
+
+        notify(line);
+        executor.run_ready_tasks();
+        assert_eq!(done.load(Ordering::Relaxed), before + 1);
+    }
+
+    #[test]
+    fn interrupt_before_the_first_poll_is_not_lost() {
+        let line = 21;
+        let before = EVENTS[line as usize].load(Ordering::Relaxed);
+        let mut executor = Executor::new();
+        // the interrupt comes in after `wait` but before the task ever ran,
+        // with no waker registered for it to wake
+        let done = spawn_waiter(&mut executor, wait(line));
+        notify(line);
+
+        executor.run_ready_tasks();
+        assert_eq!(done.load(Ordering::Relaxed), before + 1);
+
+        // a later wait doesn't complete on that interrupt again
+        let again = spawn_waiter(&mut executor, wait(line));
+        executor.run_ready_tasks();
+        assert_eq!(again.load(Ordering::Relaxed), 0);
+        notify(line);
+        notify(line);
+        executor.run_ready_tasks();
+        assert_eq!(again.load(Ordering::Relaxed), before + 3);
+    }
+}
@@ -1,5 +1,4 @@
-#![no_std]
-#![cfg_attr(test, no_main)]
+#![cfg_attr(not(test), no_std)]
 #![feature(const_fn)]
 #![feature(alloc_layout_extra)]
 #![feature(const_in_array_repeat_expressions)]
@@ -9,16 +8,23 @@
 #![feature(const_mut_refs)]
 #![feature(wake_trait)]
 #![feature(generic_associated_types)]
+#![feature(try_reserve)]
+#![feature(allocator_api)]
 
 extern crate alloc;
 
 use core::panic::PanicInfo;
+pub mod backtrace;
 pub mod console;
+pub mod extable;
 pub mod gdt;
 pub mod idt;
+pub mod irq;
 pub mod kernel_const;
 pub mod memory;
 pub mod task;
+pub mod time;
+pub mod trap;
 pub mod util;
 
 use alloc::boxed::Box;
@@ -28,7 +34,7 @@ use kernel_const::STACK_BOTTOM;
 use memory::frame_controller::FRAME_ALLOC;
 use memory::heap_allocator;
 use memory::paging::g8_page_table::PAGE_TABLE;
-use task::{executor::Executor, Task, sys_task};
+use task::{executor::Executor, sys_task};
 
 use x86_64::VirtAddr;
 
@@ -42,8 +48,12 @@ pub unsafe extern "C" fn g8start() {
     sys_task::init();
     sys_log::init();
     let mut executor = Executor::new(); // new
-    executor.spawn(Task::new(sys_task::run_sys_task()));
-    executor.spawn(Task::new(sys_log::print_log()));
+    if executor.try_spawn(sys_task::run_sys_task()).is_err() {
+        println!("no room in the executor arena for the console task");
+    }
+    if executor.try_spawn(sys_log::print_log()).is_err() {
+        println!("no room in the executor arena for the log task");
+    }
     executor.run();
 }
 
@@ -56,7 +66,11 @@ pub fn hlt_loop() -> ! {
 pub fn init() {
     gdt::init();
     idt::init_idt();
-    unsafe { idt::PICS.lock().initialize() };
+    time::init(time::TICK_HZ);
+    time::rtc::init();
+    time::tsc::init();
+    irq::init();
+    idt::init_irqs();
     x86_64::instructions::interrupts::enable();
     FRAME_ALLOC.lock().print_out();
 
@@ -83,14 +97,18 @@ fn many_boxes_alloc_test() {
     println!("[ok]");
 }
 
+#[cfg(not(test))]
 #[panic_handler]
 #[no_mangle]
 fn panic(info: &PanicInfo) -> ! {
     println!("Panic: {}", info);
+    backtrace::print_current();
     hlt_loop();
 }
 
+#[cfg(not(test))]
 #[alloc_error_handler]
 fn alloc_error_handler(layout: alloc::alloc::Layout) -> ! {
+    memory::oom::report(layout);
     panic!("allocation error: {:?}", layout)
 }
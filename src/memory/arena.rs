@@ -0,0 +1,216 @@
+//! Named arenas on top of the global heap. An arena counts what its users
+//! hold and turns allocations beyond its quota down, so a subsystem running
+//! away (a flood of log messages, ...) can't take the heap from the others.
+//! Arenas implement `Allocator`, collections use them through `new_in`:
+//!
+//! ```ignore
+//! let v: Vec<u8, _> = Vec::new_in(&LOG_ARENA);
+//! ```
+
+use crate::{print, println};
+use alloc::alloc::{self as heap, Layout};
+use alloc::vec::Vec;
+use core::alloc::{AllocError, Allocator};
+use core::fmt::{self, Error, Write};
+use core::ptr::{self, NonNull};
+use core::str;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+/// queued log messages
+pub static LOG_ARENA: Arena = Arena::new("log", 4 << 20);
+/// futures of spawned tasks
+pub static EXECUTOR_ARENA: Arena = Arena::new("executor", 4 << 20);
+/// device drivers, the keyboard and the console command line
+pub static DRIVER_ARENA: Arena = Arena::new("driver", 1 << 20);
+
+static ARENAS: [&Arena; 3] = [&LOG_ARENA, &EXECUTOR_ARENA, &DRIVER_ARENA];
+
+/// A snapshot of the arena counters, taken by `Arena::stats()`.
+#[derive(Debug, Clone, Copy)]
+pub struct ArenaStats {
+    pub name: &'static str,
+    pub quota: u64,
+    pub in_use: u64,
+    pub peak_in_use: u64,
+    pub live_allocs: u64,
+    pub total_allocs: u64,
+    pub failed_allocs: u64,
+}
+
+pub struct Arena {
+    name: &'static str,
+    quota: AtomicU64, // 0 for no quota
+    in_use: AtomicU64,
+    peak_in_use: AtomicU64,
+    live_allocs: AtomicU64,
+    total_allocs: AtomicU64,
+    failed_allocs: AtomicU64,
+}
+
+impl Arena {
+    pub const fn new(name: &'static str, quota: u64) -> Self {
+        Arena {
+            name,
+            quota: AtomicU64::new(quota),
+            in_use: AtomicU64::new(0),
+            peak_in_use: AtomicU64::new(0),
+            live_allocs: AtomicU64::new(0),
+            total_allocs: AtomicU64::new(0),
+            failed_allocs: AtomicU64::new(0),
+        }
+    }
+
+    /// 0 lifts the quota, memory already held above a new quota stays
+    pub fn set_quota(&self, quota: u64) {
+        self.quota.store(quota, Ordering::Relaxed);
+    }
+
+    pub fn stats(&self) -> ArenaStats {
+        ArenaStats {
+            name: self.name,
+            quota: self.quota.load(Ordering::Relaxed),
+            in_use: self.in_use.load(Ordering::Relaxed),
+            peak_in_use: self.peak_in_use.load(Ordering::Relaxed),
+            live_allocs: self.live_allocs.load(Ordering::Relaxed),
+            total_allocs: self.total_allocs.load(Ordering::Relaxed),
+            failed_allocs: self.failed_allocs.load(Ordering::Relaxed),
+        }
+    }
+
+    /// takes `size` bytes out of the quota
+    fn charge(&self, size: u64) -> bool {
+        let quota = self.quota.load(Ordering::Relaxed);
+        let charged = self
+            .in_use
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |in_use| {
+                if quota != 0 && in_use + size > quota {
+                    None
+                } else {
+                    Some(in_use + size)
+                }
+            });
+        match charged {
+            Ok(in_use) => {
+                self.peak_in_use.fetch_max(in_use + size, Ordering::Relaxed);
+                true
+            }
+            Err(_) => false,
+        }
+    }
+
+    fn refund(&self, size: u64) {
+        self.in_use.fetch_sub(size, Ordering::Relaxed);
+    }
+}
+
+unsafe impl Allocator for Arena {
+    fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        if layout.size() == 0 {
+            let dangling = ptr::slice_from_raw_parts_mut(layout.align() as *mut u8, 0);
+            return Ok(unsafe { NonNull::new_unchecked(dangling) });
+        }
+
+        let size = layout.size() as u64;
+        if !self.charge(size) {
+            self.failed_allocs.fetch_add(1, Ordering::Relaxed);
+            return Err(AllocError);
+        }
+        match NonNull::new(unsafe { heap::alloc(layout) }) {
+            Some(p) => {
+                self.live_allocs.fetch_add(1, Ordering::Relaxed);
+                self.total_allocs.fetch_add(1, Ordering::Relaxed);
+                let p = ptr::slice_from_raw_parts_mut(p.as_ptr(), layout.size());
+                Ok(unsafe { NonNull::new_unchecked(p) })
+            }
+            None => {
+                self.refund(size);
+                self.failed_allocs.fetch_add(1, Ordering::Relaxed);
+                Err(AllocError)
+            }
+        }
+    }
+
+    unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+        if layout.size() == 0 {
+            return;
+        }
+        heap::dealloc(ptr.as_ptr(), layout);
+        self.refund(layout.size() as u64);
+        self.live_allocs.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// A string in an arena whose writes fail instead of aborting once the
+/// arena or the heap is exhausted.
+pub struct ArenaString(Vec<u8, &'static Arena>);
+
+impl ArenaString {
+    pub fn new_in(arena: &'static Arena) -> Self {
+        ArenaString(Vec::new_in(arena))
+    }
+
+    pub fn as_str(&self) -> &str {
+        // only ever written through `write_str`
+        unsafe { str::from_utf8_unchecked(&self.0) }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.0.capacity()
+    }
+
+    pub fn clear(&mut self) {
+        self.0.clear();
+    }
+}
+
+impl Write for ArenaString {
+    fn write_str(&mut self, s: &str) -> Result<(), Error> {
+        self.0.try_reserve(s.len()).map_err(|_| Error)?;
+        self.0.extend_from_slice(s.as_bytes());
+        Ok(())
+    }
+}
+
+impl fmt::Debug for ArenaString {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(self.as_str(), f)
+    }
+}
+
+/// The `arenas` command
+pub fn print_stats() {
+    println!("arena       in use      peak     quota   live  failed");
+    for arena in ARENAS.iter() {
+        let s = arena.stats();
+        print!("{:<8} {:>8}K {:>8}K ", s.name, s.in_use >> 10, s.peak_in_use >> 10);
+        if s.quota == 0 {
+            print!("{:>9}", "none");
+        } else {
+            print!("{:>8}K", s.quota >> 10);
+        }
+        println!(" {:>6} {:>7}", s.live_allocs, s.failed_allocs);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn quota_turns_allocations_down() {
+        static ARENA: Arena = Arena::new("test", 1024);
+
+        let mut v: Vec<u8, &Arena> = Vec::new_in(&ARENA);
+        v.try_reserve_exact(1000).unwrap();
+        assert!(Vec::<u8, &Arena>::new_in(&ARENA).try_reserve_exact(100).is_err());
+        let s = ARENA.stats();
+        assert_eq!((s.in_use, s.live_allocs, s.failed_allocs), (1000, 1, 1));
+
+        drop(v);
+        let mut log = ArenaString::new_in(&ARENA);
+        assert!(log.write_str("fits").is_ok());
+        assert!(log.write_str(str::from_utf8(&[b'x'; 2048]).unwrap()).is_err());
+        assert_eq!(log.as_str(), "fits");
+        assert_eq!(ARENA.stats().peak_in_use, 1000);
+    }
+}
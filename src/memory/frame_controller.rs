@@ -1,8 +1,8 @@
 use crate::kernel_const::{
-    BOOT_TMP_MMAP_BUFFER, FRAME_SIZE, FRAME_SIZE_BIT_WIDTH, MEMORY_RESERVED_BELOW,
+    FRAME_SIZE, FRAME_SIZE_BIT_WIDTH, MEMORY_RESERVED_BELOW,
 };
 use crate::println;
-use crate::util::Locked;
+use crate::util::IrqLocked;
 use lazy_static::lazy_static;
 use x86_64::{
     structures::paging::{FrameAllocator, FrameDeallocator, PhysFrame, Size2MiB, UnusedPhysFrame},
@@ -10,13 +10,17 @@ use x86_64::{
 };
 
 lazy_static! {
-    pub static ref FRAME_ALLOC: Locked<PhysFrameAllocator> = {
+    pub static ref FRAME_ALLOC: IrqLocked<PhysFrameAllocator> = {
         let mut alloc = PhysFrameAllocator::new();
+        #[cfg(not(test))]
         unsafe {
-            let mmap = &mut *(BOOT_TMP_MMAP_BUFFER as *mut MemoryMapBuffer);
+            let mmap = &mut *(crate::kernel_const::BOOT_TMP_MMAP_BUFFER as *mut MemoryMapBuffer);
             alloc.init(&mut *mmap);
         }
-        Locked::new(alloc)
+        // host tests have no memory map, only frames nobody touches
+        #[cfg(test)]
+        alloc.add_free_block(PhysAddr::new(tests::TEST_FRAMES_START), tests::TEST_FRAMES * FRAME_SIZE);
+        IrqLocked::new(alloc)
     };
 }
 #[repr(packed)]
@@ -50,22 +54,20 @@ impl UnusedBlock {
 }
 
 const NUMBER_OF_FREE_BLOCK: u64 = 1024;
-const NUMBER_OF_FREE_MASK: u64 = 1023;
+const NO_BLOCK: Option<UnusedBlock> = None;
 
+/// The unused blocks form a list sorted by address, linked by their index in
+/// `blocks`. The list starts at `start` and ends at the empty slot its last
+/// block links to.
 pub struct PhysFrameAllocator {
     blocks: [Option<UnusedBlock>; 1024],
     start: u64,
-    end: u64,
 }
 
 impl PhysFrameAllocator {
     pub fn new() -> Self {
-        let blocks = [None; NUMBER_OF_FREE_BLOCK as usize];
-        PhysFrameAllocator {
-            blocks,
-            start: 0,
-            end: 0,
-        }
+        let blocks = [NO_BLOCK; NUMBER_OF_FREE_BLOCK as usize];
+        PhysFrameAllocator { blocks, start: 0 }
     }
 
     pub fn init(&mut self, mmap: &mut MemoryMapBuffer) {
@@ -86,10 +88,20 @@ impl PhysFrameAllocator {
         }
     }
 
-    pub fn next(&mut self) -> u64 {
-        assert!(self.end - self.start < NUMBER_OF_FREE_BLOCK);
-        self.end += 1;
-        self.end & NUMBER_OF_FREE_MASK
+    /// an empty slot for a new block, other than `except`
+    fn empty_slot(&self, except: u64) -> u64 {
+        (0..NUMBER_OF_FREE_BLOCK)
+            .find(|&i| i != except && self.blocks[i as usize].is_none())
+            .expect("no slot left for an unused block")
+    }
+
+    /// the empty slot the last block links to
+    fn tail(&self) -> u64 {
+        let mut curr = self.start;
+        while let Some(ref b) = self.blocks[curr as usize] {
+            curr = b.next;
+        }
+        curr
     }
 
     pub fn add_free_block(&mut self, addr: PhysAddr, size: u64) {
@@ -104,13 +116,32 @@ impl PhysFrameAllocator {
     }
 
     pub fn add_aligned_free_block(&mut self, addr: PhysAddr, size: u64) {
+        if size < FRAME_SIZE {
+            return;
+        }
+        let frames = size >> FRAME_SIZE_BIT_WIDTH;
+        let size = frames << FRAME_SIZE_BIT_WIDTH;
         if self.blocks[self.start as usize].is_none() {
-            let _next = self.next();
+            let _next = self.empty_slot(self.start);
             self.blocks[self.start as usize] = Some(UnusedBlock {
                 addr,
-                size: size >> FRAME_SIZE_BIT_WIDTH,
+                size: frames,
                 next: _next,
             });
+        } else if addr.as_u64() < self.blocks[self.start as usize].as_ref().unwrap().start_addr() {
+            let head = self.blocks[self.start as usize].as_mut().unwrap();
+            if addr.as_u64() + size >= head.start_addr() {
+                head.size = (head.end_addr() - addr.as_u64()) >> FRAME_SIZE_BIT_WIDTH;
+                head.addr = addr;
+            } else {
+                let _slot = self.empty_slot(self.tail());
+                self.blocks[_slot as usize] = Some(UnusedBlock {
+                    addr,
+                    size: frames,
+                    next: self.start,
+                });
+                self.start = _slot;
+            }
         } else {
             let mut cur = self.start;
             let mut next = self.blocks[cur as usize].as_ref().unwrap().next;
@@ -122,16 +153,21 @@ impl PhysFrameAllocator {
                 next = block.next;
             }
 
+            let _curr_end = self.blocks[cur as usize].as_ref().unwrap().end_addr();
+            let _curr_start = self.blocks[cur as usize].as_ref().unwrap().start_addr();
             if self.blocks[next as usize].is_none() {
-                let _next = self.next();
-                self.blocks[next as usize] = Some(UnusedBlock {
-                    addr,
-                    size: size >> FRAME_SIZE_BIT_WIDTH,
-                    next: _next,
-                });
+                if _curr_end >= addr.as_u64() {
+                    let _size = (addr.as_u64() + size - _curr_start) >> FRAME_SIZE_BIT_WIDTH;
+                    self.blocks[cur as usize].as_mut().unwrap().size = _size;
+                } else {
+                    let _next = self.empty_slot(next);
+                    self.blocks[next as usize] = Some(UnusedBlock {
+                        addr,
+                        size: frames,
+                        next: _next,
+                    });
+                }
             } else {
-                let _curr_end = self.blocks[cur as usize].as_ref().unwrap().end_addr();
-                let _curr_start = self.blocks[cur as usize].as_ref().unwrap().start_addr();
                 let _next_start = self.blocks[next as usize].as_ref().unwrap().start_addr();
                 let _next_end = self.blocks[next as usize].as_ref().unwrap().end_addr();
                 if _next_start > addr.as_u64() + size {
@@ -139,14 +175,20 @@ impl PhysFrameAllocator {
                         let _size = (addr.as_u64() + size - _curr_start) >> FRAME_SIZE_BIT_WIDTH;
                         self.blocks[cur as usize].as_mut().unwrap().size = _size;
                     } else {
-                        let _next = self.next();
-                        self.blocks[_next as usize] = Some(UnusedBlock {
+                        let _slot = self.empty_slot(self.tail());
+                        self.blocks[_slot as usize] = Some(UnusedBlock {
                             addr,
-                            size: size >> FRAME_SIZE_BIT_WIDTH,
+                            size: frames,
                             next: next,
                         });
-                        self.blocks[cur as usize].as_mut().unwrap().next = _next;
+                        self.blocks[cur as usize].as_mut().unwrap().next = _slot;
                     }
+                } else if _curr_end >= addr.as_u64() {
+                    // fills the gap between the two, they become one block
+                    let _after = self.blocks[next as usize].take().unwrap().next;
+                    let block = self.blocks[cur as usize].as_mut().unwrap();
+                    block.size = (_next_end - _curr_start) >> FRAME_SIZE_BIT_WIDTH;
+                    block.next = _after;
                 } else {
                     let _size = (_next_end - addr.as_u64()) >> FRAME_SIZE_BIT_WIDTH;
                     self.blocks[next as usize].as_mut().unwrap().addr = addr;
@@ -157,7 +199,7 @@ impl PhysFrameAllocator {
     }
 
     pub fn print_out(&mut self) {
-        println!("frame allocatr start:{}, end:{}", self.start, self.end);
+        println!("frame allocatr start:{}, free frames:{}", self.start, self.free_frames());
         let mut curr = self.start;
         while let Some(ref b) = self.blocks[curr as usize] {
             println!(
@@ -171,6 +213,17 @@ impl PhysFrameAllocator {
         }
     }
 
+    /// number of free frames left in the unused blocks
+    pub fn free_frames(&self) -> u64 {
+        let mut frames = 0;
+        let mut curr = self.start;
+        while let Some(ref b) = self.blocks[curr as usize] {
+            frames += b.size;
+            curr = b.next;
+        }
+        frames
+    }
+
     pub fn deallocate(&mut self, frame: PhysFrame<Size2MiB>) {
         unsafe {
             self.deallocate_frame(UnusedPhysFrame::new(frame));
@@ -186,11 +239,13 @@ unsafe impl FrameAllocator<Size2MiB> for PhysFrameAllocator {
     fn allocate_frame(&mut self) -> Option<UnusedPhysFrame<Size2MiB>> {
         if let Some(ref mut block) = self.blocks[self.start as usize] {
             let addr = block.addr;
-            if block.size >= 1 {
-                block.addr = addr + FRAME_SIZE;
-                block.size -= 1;
-            } else {
-                self.start = block.next;
+            block.addr = addr + FRAME_SIZE;
+            block.size -= 1;
+            if block.size == 0 {
+                // used up, the next block becomes the first
+                let _next = block.next;
+                self.blocks[self.start as usize] = None;
+                self.start = _next;
             }
             unsafe { Some(UnusedPhysFrame::new(PhysFrame::containing_address(addr))) }
         } else {
@@ -203,7 +258,7 @@ impl FrameDeallocator<Size2MiB> for PhysFrameAllocator {
     fn deallocate_frame(&mut self, frame: UnusedPhysFrame<Size2MiB>) {
         let start = frame.frame().start_address();
 
-        self.add_free_block(start, 1);
+        self.add_free_block(start, FRAME_SIZE);
     }
 }
 
@@ -218,3 +273,47 @@ fn align_down(addr: u64) -> u64 {
 fn is_align(addr: u64) -> bool {
     align_down(addr) == addr
 }
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use spin::Mutex;
+
+    pub const TEST_FRAMES_START: u64 = 0x4000_0000;
+    pub const TEST_FRAMES: u64 = 16;
+
+    /// held by tests which count the frames of `FRAME_ALLOC`, the test
+    /// harness runs them in parallel
+    pub static FRAME_ALLOC_TEST: Mutex<()> = Mutex::new(());
+
+    #[test]
+    fn freed_frames_are_allocated_again() {
+        let _serial = FRAME_ALLOC_TEST.lock();
+        let mut frames = FRAME_ALLOC.lock();
+        let free = frames.free_frames();
+
+        let a = frames.allocate().unwrap().frame();
+        let b = frames.allocate().unwrap().frame();
+        let c = frames.allocate().unwrap().frame();
+        frames.deallocate(a);
+        assert_eq!(frames.free_frames(), free - 2);
+        assert_eq!(frames.allocate().unwrap().frame(), a);
+
+        // out of order, the middle one first
+        frames.deallocate(b);
+        frames.deallocate(a);
+        frames.deallocate(c);
+        assert_eq!(frames.free_frames(), free);
+        assert_eq!(frames.allocate().unwrap().frame(), a);
+        frames.deallocate(a);
+
+        // far more frees than there are slots for blocks
+        for _ in 0..2 * NUMBER_OF_FREE_BLOCK {
+            let x = frames.allocate().unwrap().frame();
+            let y = frames.allocate().unwrap().frame();
+            frames.deallocate(x);
+            frames.deallocate(y);
+        }
+        assert_eq!(frames.free_frames(), free);
+    }
+}
@@ -0,0 +1,92 @@
+//! Heap debugging, built in with the `heap_debug` feature.
+//!
+//! Every allocation is wrapped in a redzone of one heap block on either side.
+//! The front redzone records the requested size and alignment, the rest of
+//! both redzones (including the rounding slack behind the allocation) is
+//! filled with `REDZONE_BYTE` and checked on free. Frees of blocks which the
+//! mask doesn't show as allocated or which lie in a released hole are
+//! reported and ignored, and freed memory is poisoned with `POISON_BYTE`.
+
+use super::{HeapAllocator, HeapProvider, HEAP_BLOCK_SIZE, HEAP_BLOCK_SIZE_BW};
+use crate::println;
+use alloc::alloc::Layout;
+use core::ptr;
+
+pub const REDZONE: u64 = HEAP_BLOCK_SIZE;
+const REDZONE_BYTE: u8 = 0xfd;
+const POISON_BYTE: u8 = 0xdd;
+
+/// Fills the redzones of a freshly allocated block and returns the pointer
+/// handed out to the caller.
+pub unsafe fn arm(block: *mut u8, layout: Layout, size: u64) -> *mut u8 {
+    if block.is_null() {
+        return block;
+    }
+
+    ptr::write_bytes(block, REDZONE_BYTE, REDZONE as usize);
+    (block as *mut u64).write(layout.size() as u64);
+    (block as *mut u64).add(1).write(layout.align() as u64);
+
+    let user = block.add(REDZONE as usize);
+    let tail = user.add(layout.size());
+    ptr::write_bytes(tail, REDZONE_BYTE, (block as u64 + size - tail as u64) as usize);
+    user
+}
+
+/// Validates a pointer about to be freed and poisons its block. Returns the
+/// start of the block to free, or `None` if the free must be ignored.
+pub unsafe fn check<P: HeapProvider>(
+    heap: &mut HeapAllocator<P>,
+    ptr: *mut u8,
+    layout: Layout,
+    size: u64,
+) -> Option<*mut u8> {
+    let addr = ptr as u64;
+    let block = addr.wrapping_sub(REDZONE);
+    if addr < heap.heap_start + REDZONE
+        || block + size > heap.boundry_addr()
+        || block & (HEAP_BLOCK_SIZE - 1) != 0
+    {
+        println!("HEAP: free of non heap pointer 0x{:x}, {:?}", addr, layout);
+        return None;
+    }
+
+    // released holes are unmapped but stay set in the mask
+    let released = heap.holes[..heap.n_holes]
+        .iter()
+        .any(|h| block < h.addr + h.size && block + size > h.addr);
+    if released {
+        println!("HEAP: double free of 0x{:x} in released memory, {:?}", addr, layout);
+        return None;
+    }
+
+    let s_off = (block - heap.heap_start) >> HEAP_BLOCK_SIZE_BW;
+    let e_off = s_off + (size >> HEAP_BLOCK_SIZE_BW);
+    if (s_off..e_off).any(|off| !heap.mask.is_set(off)) {
+        println!("HEAP: double free or invalid free of 0x{:x}, {:?}", addr, layout);
+        return None;
+    }
+
+    let _block = block as *mut u8;
+    let recorded = ((_block as *const u64).read(), (_block as *const u64).add(1).read());
+    if recorded != (layout.size() as u64, layout.align() as u64) {
+        println!(
+            "HEAP: 0x{:x} freed with {:?}, allocated with size {} align {}",
+            addr, layout, recorded.0, recorded.1
+        );
+    }
+    if !is_filled(_block.add(16), REDZONE as usize - 16) {
+        println!("HEAP: underflow before 0x{:x}, {:?}", addr, layout);
+    }
+    let tail = ptr.add(layout.size());
+    if !is_filled(tail, (block + size - tail as u64) as usize) {
+        println!("HEAP: overflow after 0x{:x}, {:?}", addr, layout);
+    }
+
+    ptr::write_bytes(_block, POISON_BYTE, size as usize);
+    Some(_block)
+}
+
+unsafe fn is_filled(p: *const u8, len: usize) -> bool {
+    (0..len).all(|i| p.add(i).read() == REDZONE_BYTE)
+}
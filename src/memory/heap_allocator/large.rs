@@ -0,0 +1,167 @@
+//! Allocations of `HEAP_LARGE_THRESHOLD` bytes and more bypass the heap. Each
+//! gets a run of whole frames in a virtual range of its own, mapped when it
+//! is allocated and given straight back to the provider when it is freed, so
+//! big buffers neither bloat the heap mask nor fragment the free lists.
+
+use super::{frame_align_up, HeapProvider, HEAP_LARGE_SLOTS};
+use crate::kernel_const::FRAME_SIZE_BIT_WIDTH;
+use core::ptr;
+
+const LARGE_SLOT_WORDS: usize = (HEAP_LARGE_SLOTS as usize + 63) / 64;
+
+/// A snapshot of the large allocation counters.
+#[derive(Debug, Clone, Copy)]
+pub struct LargeStats {
+    pub mapped: u64,
+    pub peak_mapped: u64,
+    pub live_allocs: u64,
+    pub total_allocs: u64,
+    pub failed_allocs: u64,
+}
+
+/// Hands out runs of frames from `slots` frames of virtual space starting at
+/// `start`, which must be frame aligned. A set bit in `used` marks a frame
+/// which is mapped and belongs to an allocation.
+pub struct LargeAllocator<P> {
+    pub(super) provider: P,
+    start: u64,
+    slots: u64,
+    used: [u64; LARGE_SLOT_WORDS],
+    mapped: u64,
+    peak_mapped: u64,
+    live_allocs: u64,
+    total_allocs: u64,
+    failed_allocs: u64,
+}
+
+impl<P> LargeAllocator<P> {
+    pub const fn new(start: u64, slots: u64, provider: P) -> Self {
+        LargeAllocator {
+            provider,
+            start,
+            slots,
+            used: [0; LARGE_SLOT_WORDS],
+            mapped: 0,
+            peak_mapped: 0,
+            live_allocs: 0,
+            total_allocs: 0,
+            failed_allocs: 0,
+        }
+    }
+}
+
+impl<P: HeapProvider> LargeAllocator<P> {
+    pub fn contains(&self, addr: u64) -> bool {
+        addr >= self.start && addr < self.start + (self.slots << FRAME_SIZE_BIT_WIDTH)
+    }
+
+    fn is_used(&self, i: u64) -> bool {
+        self.used[(i >> 6) as usize] & (1 << (i & 63)) != 0
+    }
+
+    fn set_used(&mut self, i: u64, n: u64, on: bool) {
+        for i in i..i + n {
+            if on {
+                self.used[(i >> 6) as usize] |= 1 << (i & 63);
+            } else {
+                self.used[(i >> 6) as usize] &= !(1 << (i & 63));
+            }
+        }
+    }
+
+    fn slot_addr(&self, i: u64) -> u64 {
+        self.start + (i << FRAME_SIZE_BIT_WIDTH)
+    }
+
+    /// first fit, large allocations are few
+    fn find_run(&self, n: u64) -> Option<u64> {
+        let mut run = 0;
+        for i in 0..self.slots {
+            if self.is_used(i) {
+                run = 0;
+            } else {
+                run += 1;
+                if run == n {
+                    return Some(i + 1 - n);
+                }
+            }
+        }
+        None
+    }
+
+    /// maps slots `i..i + n`, or none of them
+    fn map_run(&mut self, i: u64, n: u64) -> bool {
+        for j in 0..n {
+            if self.provider.map(self.slot_addr(i + j)).is_err() {
+                for k in 0..j {
+                    self.provider.unmap(self.slot_addr(i + k));
+                }
+                return false;
+            }
+        }
+        self.set_used(i, n, true);
+        self.mapped += n << FRAME_SIZE_BIT_WIDTH;
+        self.peak_mapped = core::cmp::max(self.peak_mapped, self.mapped);
+        true
+    }
+
+    fn unmap_run(&mut self, i: u64, n: u64) {
+        for j in i..i + n {
+            self.provider.unmap(self.slot_addr(j));
+        }
+        self.set_used(i, n, false);
+        self.mapped -= n << FRAME_SIZE_BIT_WIDTH;
+    }
+
+    /// returns null when there is no room in the range or no frame left
+    pub fn alloc(&mut self, size: u64) -> *mut u8 {
+        let n = frame_align_up(size) >> FRAME_SIZE_BIT_WIDTH;
+        match self.find_run(n) {
+            Some(i) if self.map_run(i, n) => {
+                self.live_allocs += 1;
+                self.total_allocs += 1;
+                self.slot_addr(i) as *mut u8
+            }
+            _ => {
+                self.failed_allocs += 1;
+                ptr::null_mut()
+            }
+        }
+    }
+
+    pub fn dealloc(&mut self, addr: u64, size: u64) {
+        let i = (addr - self.start) >> FRAME_SIZE_BIT_WIDTH;
+        let n = frame_align_up(size) >> FRAME_SIZE_BIT_WIDTH;
+        self.unmap_run(i, n);
+        self.live_allocs -= 1;
+    }
+
+    /// Shrinking unmaps the frames no longer needed, growing maps the frames
+    /// right behind the allocation if they are free. Returns false, changing
+    /// nothing, when they are not.
+    pub fn resize_in_place(&mut self, addr: u64, old_size: u64, new_size: u64) -> bool {
+        let i = (addr - self.start) >> FRAME_SIZE_BIT_WIDTH;
+        let old_n = frame_align_up(old_size) >> FRAME_SIZE_BIT_WIDTH;
+        let new_n = frame_align_up(new_size) >> FRAME_SIZE_BIT_WIDTH;
+        if new_n <= old_n {
+            self.unmap_run(i + new_n, old_n - new_n);
+            return true;
+        }
+
+        let e = i + new_n;
+        if e > self.slots || (i + old_n..e).any(|j| self.is_used(j)) {
+            return false;
+        }
+        self.map_run(i + old_n, new_n - old_n)
+    }
+
+    pub fn stats(&self) -> LargeStats {
+        LargeStats {
+            mapped: self.mapped,
+            peak_mapped: self.peak_mapped,
+            live_allocs: self.live_allocs,
+            total_allocs: self.total_allocs,
+            failed_allocs: self.failed_allocs,
+        }
+    }
+}
@@ -0,0 +1,931 @@
+use crate::kernel_const::{FRAME_SIZE, FRAME_SIZE_BIT_WIDTH};
+use crate::memory::frame_controller::FRAME_ALLOC;
+use crate::memory::oom;
+use crate::memory::paging::g8_page_table::PAGE_TABLE;
+use crate::println;
+use crate::util::IrqLocked;
+use large::{LargeAllocator, LargeStats};
+use alloc::alloc::{GlobalAlloc, Layout};
+use core::ptr;
+use x86_64::{
+    structures::paging::{mapper::MapToError, PageTableFlags, Size2MiB},
+    VirtAddr,
+};
+use crate::no_interrupt;
+use spin::Mutex;
+use lazy_static::lazy_static;
+
+#[cfg(feature = "heap_debug")]
+mod debug;
+pub mod large;
+#[cfg(test)]
+mod tests;
+pub mod track;
+
+const HEAP_MAX_BLOCKS: u64 = 0x4000000; // max heap size 128G
+const HEAP_BLOCK_SIZE: u64 = 64; // matches cache line
+const HEAP_BLOCK_SIZE_BW: u64 = 6; // bit width of heap block size
+const HEAP_MASK_START_ADDR: u64 = 0x20000000;
+const HEAP_START_ADDR: u64 = 0x40000000;
+const HEAP_LOW_WATER_MARK: u64 = 32 * FRAME_SIZE; // keep 64M mapped before giving frames back
+const HEAP_MAX_HOLES: usize = 64;
+const HEAP_MIN_INCREMENT: u64 = 8 * FRAME_SIZE; // grow by at least 16M
+const HEAP_DEFAULT_MAX_SIZE: u64 = HEAP_MAX_BLOCKS << HEAP_BLOCK_SIZE_BW;
+const HEAP_HISTOGRAM_BUCKETS: usize = 16; // 64B up to 2M and above
+const HEAP_MAX_SIZE_LIMIT: u64 = (HEAP_START_ADDR - HEAP_MASK_START_ADDR) << 3 << HEAP_BLOCK_SIZE_BW;
+const HEAP_LARGE_THRESHOLD: u64 = FRAME_SIZE; // allocations this big get frames of their own
+const HEAP_LARGE_START_ADDR: u64 = 0x8000000000;
+const HEAP_LARGE_SLOTS: u64 = 4096; // 8G of virtual space for large allocations
+const HEAP_SL_BW: u64 = 3; // every power of two of sizes is split into 8 free lists
+const HEAP_SL_COUNT: usize = 1 << HEAP_SL_BW;
+const HEAP_FL_COUNT: usize = (64 - HEAP_MAX_SIZE_LIMIT.leading_zeros() as u64 - HEAP_BLOCK_SIZE_BW) as usize;
+const EMPTY_LIST: FreeBlock = FreeBlock {
+    size: 0,
+    prev: None,
+    next: None,
+};
+
+#[cfg_attr(not(test), global_allocator)]
+static ALLOCATOR: IrqLocked<HeapAllocator<FrameProvider>> = IrqLocked::new(HeapAllocator::new(
+    HEAP_START_ADDR,
+    HEAP_MASK_START_ADDR,
+    FrameProvider,
+));
+static LARGE: IrqLocked<LargeAllocator<FrameProvider>> = IrqLocked::new(LargeAllocator::new(
+    HEAP_LARGE_START_ADDR,
+    HEAP_LARGE_SLOTS,
+    FrameProvider,
+));
+
+// lazy_static!{
+//     static ref MASK: Mutex<BitMask> = unsafe{ 
+//         Mutex::new(BitMask {
+//                         size: 0,
+//                         inner: &mut *(HEAP_MASK_START_ADDR as *mut [u64; HEAP_MAX_BLOCKS as usize]),
+//                     })
+//         };
+// }
+
+
+const MASK_64: [u64; 65] = [
+                            0b0,
+                            0b1000000000000000000000000000000000000000000000000000000000000000,
+                            0b1100000000000000000000000000000000000000000000000000000000000000,
+                            0b1110000000000000000000000000000000000000000000000000000000000000,
+                            0b1111000000000000000000000000000000000000000000000000000000000000,
+                            0b1111100000000000000000000000000000000000000000000000000000000000,
+                            0b1111110000000000000000000000000000000000000000000000000000000000,
+                            0b1111111000000000000000000000000000000000000000000000000000000000,
+                            0b1111111100000000000000000000000000000000000000000000000000000000,
+                            0b1111111110000000000000000000000000000000000000000000000000000000,
+                            0b1111111111000000000000000000000000000000000000000000000000000000,
+                            0b1111111111100000000000000000000000000000000000000000000000000000,
+                            0b1111111111110000000000000000000000000000000000000000000000000000,
+                            0b1111111111111000000000000000000000000000000000000000000000000000,
+                            0b1111111111111100000000000000000000000000000000000000000000000000,
+                            0b1111111111111110000000000000000000000000000000000000000000000000,
+                            0b1111111111111111000000000000000000000000000000000000000000000000,
+                            0b1111111111111111100000000000000000000000000000000000000000000000,
+                            0b1111111111111111110000000000000000000000000000000000000000000000,
+                            0b1111111111111111111000000000000000000000000000000000000000000000,
+                            0b1111111111111111111100000000000000000000000000000000000000000000,
+                            0b1111111111111111111110000000000000000000000000000000000000000000,
+                            0b1111111111111111111111000000000000000000000000000000000000000000,
+                            0b1111111111111111111111100000000000000000000000000000000000000000,
+                            0b1111111111111111111111110000000000000000000000000000000000000000,
+                            0b1111111111111111111111111000000000000000000000000000000000000000,
+                            0b1111111111111111111111111100000000000000000000000000000000000000,
+                            0b1111111111111111111111111110000000000000000000000000000000000000,
+                            0b1111111111111111111111111111000000000000000000000000000000000000,
+                            0b1111111111111111111111111111100000000000000000000000000000000000,
+                            0b1111111111111111111111111111110000000000000000000000000000000000,
+                            0b1111111111111111111111111111111000000000000000000000000000000000,
+                            0b1111111111111111111111111111111100000000000000000000000000000000,
+                            0b1111111111111111111111111111111110000000000000000000000000000000,
+                            0b1111111111111111111111111111111111000000000000000000000000000000,
+                            0b1111111111111111111111111111111111100000000000000000000000000000,
+                            0b1111111111111111111111111111111111110000000000000000000000000000,
+                            0b1111111111111111111111111111111111111000000000000000000000000000,
+                            0b1111111111111111111111111111111111111100000000000000000000000000,
+                            0b1111111111111111111111111111111111111110000000000000000000000000,
+                            0b1111111111111111111111111111111111111111000000000000000000000000,
+                            0b1111111111111111111111111111111111111111100000000000000000000000,
+                            0b1111111111111111111111111111111111111111110000000000000000000000,
+                            0b1111111111111111111111111111111111111111111000000000000000000000,
+                            0b1111111111111111111111111111111111111111111100000000000000000000,
+                            0b1111111111111111111111111111111111111111111110000000000000000000,
+                            0b1111111111111111111111111111111111111111111111000000000000000000,
+                            0b1111111111111111111111111111111111111111111111100000000000000000,
+                            0b1111111111111111111111111111111111111111111111110000000000000000,
+                            0b1111111111111111111111111111111111111111111111111000000000000000,
+                            0b1111111111111111111111111111111111111111111111111100000000000000,
+                            0b1111111111111111111111111111111111111111111111111110000000000000,
+                            0b1111111111111111111111111111111111111111111111111111000000000000,
+                            0b1111111111111111111111111111111111111111111111111111100000000000,
+                            0b1111111111111111111111111111111111111111111111111111110000000000,
+                            0b1111111111111111111111111111111111111111111111111111111000000000,
+                            0b1111111111111111111111111111111111111111111111111111111100000000,
+                            0b1111111111111111111111111111111111111111111111111111111110000000,
+                            0b1111111111111111111111111111111111111111111111111111111111000000,
+                            0b1111111111111111111111111111111111111111111111111111111111100000,
+                            0b1111111111111111111111111111111111111111111111111111111111110000,
+                            0b1111111111111111111111111111111111111111111111111111111111111000,
+                            0b1111111111111111111111111111111111111111111111111111111111111100,
+                            0b1111111111111111111111111111111111111111111111111111111111111110,
+                            0b1111111111111111111111111111111111111111111111111111111111111111,
+                        ];
+
+struct BitMask {
+    start: u64,
+    size: u64,
+}
+
+impl BitMask {
+    fn boudry_addr(&self) -> u64 {
+        self.start + (self.size >> 3)
+    }
+
+    fn split_pos(&self, pos: u64) -> (u64, u8) {
+        assert!(pos < self.size);
+        (pos >> 6, (pos & 0x3f) as u8)
+    }
+
+    unsafe fn is_set(&self, pos: u64) -> bool {
+        let (p, m) = self.split_pos(pos);
+        let _m = get_mask_in_u64(m, m);
+        let v = get_u64(self.start+p*8);
+        let r = v & _m != 0;
+        // println!("is_set, v:0x{:x}, _m:0x{:x}, r: {}", v, _m, r);
+        r
+    }
+
+    fn is_empty(&self) -> bool {
+        self.size == 0
+    }
+
+    unsafe fn range_off(&mut self, s: u64, e: u64) {
+        let start = self.start;
+        self.set_by_u64(s, e, 
+            move |_s, _e, i| set_u64(start+i*8, get_u64(start+i*8)&!get_mask_in_u64( _s, _e)));
+    }
+
+    unsafe fn range_on(&mut self, s: u64, e: u64) {
+        let start = self.start;
+        self.set_by_u64(s, e, 
+            move |_s, _e, i| set_u64(start+i*8, get_u64(start+i*8)|get_mask_in_u64(_s,_e)));
+        
+    }
+
+    unsafe fn set_by_u64<F>(&mut self, s: u64, e: u64, f: F)
+        where F: FnOnce(u8, u8, u64) -> () + Copy {
+        assert!(s <= e);
+        let (p1, q1) = self.split_pos(s);
+        let (p2, q2) = self.split_pos(e);
+        
+        if p1 == p2 {
+            f(q1, q2, p1 as u64);
+        }else {
+            for i in p1..=p2 {
+                match i {
+                    _ if i == p1 => f(q1, 63, i as u64),
+                    _ if i == p2 => f(0, q2, i as u64),
+                    _ => f(0, 63, i as u64),
+                }
+            }
+        }
+    }
+}
+
+unsafe fn get_u64(addr: u64) -> u64{
+    (addr as *const u64).read()
+}
+
+unsafe fn set_u64( addr: u64, v: u64) {
+    (addr as *mut u64).write(v);
+}
+
+fn get_mask_in_u64(s: u8, e: u8) -> u64{
+    assert!(s>=0 && s<64);
+    assert!(e>=0 && e<64);
+    assert!(s<=e);
+    !MASK_64[s as usize] & MASK_64[(e+1) as usize]
+}
+
+struct FreeBlock {
+    size: u64,
+    prev: Option<&'static mut FreeBlock>,
+    next: Option<&'static mut FreeBlock>,
+}
+
+impl FreeBlock {
+    fn start_addr(&self) -> u64 {
+        self as *const Self as u64
+    }
+
+    fn end_addr(&self) -> u64 {
+        self.start_addr() + self.size
+    }
+
+    unsafe fn release(&mut self) -> *mut u8 {
+        let mut _prev = self.prev.take();
+        let mut _next = self.next.take();
+        if let Some(ref mut next) = _next {
+            next.prev = _prev
+                .as_ref()
+                .map(|p| &mut *(p.start_addr() as *mut FreeBlock));
+        }
+        if let Some(ref mut prev) = _prev {
+            prev.next = _next;
+        }
+        self.start_addr() as *mut u8
+    }
+
+    unsafe fn write_addr_at_end(&self) {
+        let _e_ptr = (self.end_addr() - 8) as *mut u64;
+        _e_ptr.write(self.start_addr());
+    }
+}
+
+/// A frame aligned range inside the heap whose frames have been handed back
+/// to `FRAME_ALLOC`. The mask keeps it marked as used, so no free block will
+/// ever merge into it until it is mapped again.
+#[derive(Clone, Copy)]
+struct HeapHole {
+    addr: u64,
+    size: u64,
+}
+
+/// A snapshot of the heap counters, taken by `stats()`.
+#[derive(Debug, Clone, Copy)]
+pub struct HeapStats {
+    pub heap_size: u64,
+    pub mapped: u64,
+    pub in_use: u64,
+    pub peak_in_use: u64,
+    pub live_allocs: u64,
+    pub total_allocs: u64,
+    pub total_frees: u64,
+    pub failed_allocs: u64,
+    pub free_blocks: u64,
+    pub free_bytes: u64,
+    pub largest_free: u64,
+    /// free blocks by size, bucket `i` counts blocks of `64 << i` bytes and up
+    pub free_histogram: [u64; HEAP_HISTOGRAM_BUCKETS],
+}
+
+/// Backs the heap with memory, one `FRAME_SIZE` frame at a time. The kernel
+/// maps frames from `FRAME_ALLOC` into `PAGE_TABLE`, host tests hand out
+/// pieces of a `Vec`.
+pub trait HeapProvider {
+    /// makes the frame starting at `addr` usable
+    fn map(&mut self, addr: u64) -> Result<(), MapToError<Size2MiB>>;
+    /// gives the memory behind the frame starting at `addr` back
+    fn unmap(&mut self, addr: u64);
+}
+
+pub struct FrameProvider;
+
+impl HeapProvider for FrameProvider {
+    fn map(&mut self, addr: u64) -> Result<(), MapToError<Size2MiB>> {
+        let frame = FRAME_ALLOC
+            .lock()
+            .allocate()
+            .ok_or(MapToError::FrameAllocationFailed)?;
+        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+        let result = PAGE_TABLE.lock().map_to(VirtAddr::new(addr), frame, flags);
+        match result {
+            Ok(flusher) => {
+                flusher.flush();
+                Ok(())
+            }
+            Err(e) => {
+                FRAME_ALLOC.lock().deallocate(frame);
+                Err(e)
+            }
+        }
+    }
+
+    fn unmap(&mut self, addr: u64) {
+        if let Ok((frame, flusher)) = PAGE_TABLE.lock().unmap(VirtAddr::new(addr)) {
+            flusher.flush();
+            FRAME_ALLOC.lock().deallocate(frame);
+        }
+    }
+}
+
+/// The heap lives at `heap_start` with its occupancy mask at `mask_start`,
+/// both must be frame aligned. The allocator must not move once it has been
+/// expanded, the free lists link back to `lists`.
+///
+/// Free blocks are segregated by size, TLSF style: the first level splits
+/// sizes by powers of two, the second splits each power into `HEAP_SL_COUNT`
+/// lists. `fl_bitmap` and `sl_bitmap` record which lists are non empty, so
+/// neither allocating nor freeing ever walks a list.
+pub struct HeapAllocator<P> {
+    provider: P,
+    heap_start: u64,
+    lists: [FreeBlock; HEAP_FL_COUNT * HEAP_SL_COUNT],
+    fl_bitmap: u64,
+    sl_bitmap: [u8; HEAP_FL_COUNT],
+    mask: BitMask,
+    size: u64,
+    holes: [HeapHole; HEAP_MAX_HOLES],
+    n_holes: usize,
+    released: u64,
+    low_water: u64,
+    min_increment: u64,
+    max_size: u64,
+    in_use: u64,
+    peak_in_use: u64,
+    live_allocs: u64,
+    total_allocs: u64,
+    total_frees: u64,
+    failed_allocs: u64,
+}
+
+impl<P> HeapAllocator<P> {
+    pub const fn new(heap_start: u64, mask_start: u64, provider: P) -> Self {
+        Self {
+            provider,
+            heap_start,
+            lists: [EMPTY_LIST; HEAP_FL_COUNT * HEAP_SL_COUNT],
+            fl_bitmap: 0,
+            sl_bitmap: [0; HEAP_FL_COUNT],
+            mask: BitMask {
+                start: mask_start,
+                size: 0,
+            },
+            size: 0,
+            holes: [HeapHole { addr: 0, size: 0 }; HEAP_MAX_HOLES],
+            n_holes: 0,
+            released: 0,
+            low_water: HEAP_LOW_WATER_MARK,
+            min_increment: HEAP_MIN_INCREMENT,
+            max_size: HEAP_DEFAULT_MAX_SIZE,
+            in_use: 0,
+            peak_in_use: 0,
+            live_allocs: 0,
+            total_allocs: 0,
+            total_frees: 0,
+            failed_allocs: 0,
+        }
+    }
+
+}
+
+impl<P: HeapProvider> HeapAllocator<P> {
+    pub fn boundry_addr(&self) -> u64 {
+        self.heap_start + self.size
+    }
+
+    /// bytes of the heap currently backed by physical frames
+    pub fn mapped_size(&self) -> u64 {
+        self.size - self.released
+    }
+
+    /// Makes room for at least `size` more bytes, either by mapping a released
+    /// hole back in or by growing the heap at its boundary. The heap grows by
+    /// no less than `min_increment` and never beyond `max_size`.
+    pub unsafe fn expand(&mut self, size: u64) -> Result<(), MapToError<Size2MiB>> {
+        // println!("expand");
+        let need = frame_align_up(size);
+        if need > 0 {
+            if let Some(i) = self.holes[..self.n_holes].iter().position(|h| h.size >= need) {
+                return self.refill_hole(i, need);
+            }
+        }
+
+        if self.size + need > self.max_size {
+            return Err(MapToError::FrameAllocationFailed);
+        }
+        let grow = core::cmp::min(
+            core::cmp::max(need, frame_align_up(self.min_increment)),
+            self.max_size - self.size,
+        );
+
+        while self.mask.size < (self.size + grow) >> HEAP_BLOCK_SIZE_BW {
+            self.expand_mask()?;
+        }
+
+        let mut _size = 0;
+        let mut result = Ok(());
+        let s_addr = self.boundry_addr();
+        while _size < grow {
+            if let Err(e) = self.provider.map(s_addr + _size) {
+                result = Err(e);
+                break;
+            }
+            _size += FRAME_SIZE;
+        }
+
+        // keep whatever got mapped before a failure
+        if _size > 0 {
+            self.size += _size;
+            self.ins_merg_free_block(s_addr, _size);
+        }
+        result
+    }
+
+    /// maps the first `size` bytes of hole `i` back in and returns them to the free list
+    unsafe fn refill_hole(&mut self, i: usize, size: u64) -> Result<(), MapToError<Size2MiB>> {
+        let hole = self.holes[i];
+
+        let mut _size = 0;
+        let mut result = Ok(());
+        while _size < size {
+            if let Err(e) = self.provider.map(hole.addr + _size) {
+                result = Err(e);
+                break;
+            }
+            _size += FRAME_SIZE;
+        }
+
+        if _size == hole.size {
+            self.n_holes -= 1;
+            self.holes[i] = self.holes[self.n_holes];
+        } else {
+            self.holes[i] = HeapHole {
+                addr: hole.addr + _size,
+                size: hole.size - _size,
+            };
+        }
+        if _size > 0 {
+            self.released -= _size;
+            self.ins_merg_free_block(hole.addr, _size);
+        }
+        result
+    }
+
+    unsafe fn dealloc(&mut self, addr: u64, size: u64) {
+        self.in_use -= size;
+        self.live_allocs -= 1;
+        self.total_frees += 1;
+        self.free_block(addr, size);
+    }
+
+    unsafe fn free_block(&mut self, addr: u64, size: u64) {
+        let _addr = self.ins_merg_free_block(addr, size);
+        self.release_unused(_addr);
+    }
+
+    /// Unmaps the frame aligned span inside the free block at `addr` and gives
+    /// the frames back to `FRAME_ALLOC`, as long as the heap stays above the
+    /// low-water mark. What is left on either side of the span stays free.
+    unsafe fn release_unused(&mut self, addr: u64) {
+        if self.n_holes >= HEAP_MAX_HOLES || self.mapped_size() <= self.low_water {
+            return;
+        }
+
+        let block = &*(addr as *const FreeBlock);
+        let s_addr = block.start_addr();
+        let e_addr = block.end_addr();
+        let r_start = frame_align_up(s_addr);
+        let r_end = e_addr >> FRAME_SIZE_BIT_WIDTH << FRAME_SIZE_BIT_WIDTH;
+        if r_end <= r_start {
+            return;
+        }
+
+        let frames = core::cmp::min(
+            (r_end - r_start) >> FRAME_SIZE_BIT_WIDTH,
+            (self.mapped_size() - self.low_water) >> FRAME_SIZE_BIT_WIDTH,
+        );
+        if frames == 0 {
+            return;
+        }
+        let r_start = r_end - (frames << FRAME_SIZE_BIT_WIDTH);
+
+        self.remove_free_block(s_addr);
+        self.mask_on(s_addr, e_addr - s_addr);
+        if r_start > s_addr {
+            self.add_free_block(s_addr, r_start - s_addr);
+            self.mask_off(s_addr, r_start - s_addr);
+        }
+        if e_addr > r_end {
+            self.add_free_block(r_end, e_addr - r_end);
+            self.mask_off(r_end, e_addr - r_end);
+        }
+
+        for _addr in (r_start..r_end).step_by(FRAME_SIZE as usize) {
+            self.provider.unmap(_addr);
+        }
+        self.released += r_end - r_start;
+        self.holes[self.n_holes] = HeapHole {
+            addr: r_start,
+            size: r_end - r_start,
+        };
+        self.n_holes += 1;
+    }
+
+    /// returns the start address of the free block which `addr` ended up in
+    unsafe fn ins_merg_free_block(&mut self, addr: u64, size: u64) -> u64 {
+        // println!("ins_merg_free_block, addr:0x{:x}, size:{}", addr, size);
+        let s_off = (addr - self.heap_start) >> HEAP_BLOCK_SIZE_BW;
+        let e_off = (addr + size - self.heap_start) >> HEAP_BLOCK_SIZE_BW;
+        // println!("ins_merg_free_block");
+        let heap_blocks = self.size >> HEAP_BLOCK_SIZE_BW;
+        let mut _start = addr;
+        let mut _end = addr + size;
+        if s_off > 0 && !self.mask.is_set(s_off - 1) {
+            _start = ((addr - 8) as *const u64).read();
+            self.remove_free_block(_start);
+        }
+        if e_off < heap_blocks && !self.mask.is_set(e_off) {
+            _end += (*(_end as *const FreeBlock)).size;
+            self.remove_free_block(addr + size);
+        }
+        self.add_free_block(_start, _end - _start);
+
+        self.mask.range_off(s_off, e_off-1);
+        _start
+    }
+
+    unsafe fn mask_on(&mut self, addr: u64, size: u64) {
+        let s_off = (addr - self.heap_start) >> HEAP_BLOCK_SIZE_BW;
+        let e_off = ((addr + size - self.heap_start) >> HEAP_BLOCK_SIZE_BW) - 1;
+        self.mask.range_on(s_off, e_off);
+    }
+
+    unsafe fn mask_off(&mut self, addr: u64, size: u64) {
+        let s_off = (addr - self.heap_start) >> HEAP_BLOCK_SIZE_BW;
+        let e_off = ((addr + size - self.heap_start) >> HEAP_BLOCK_SIZE_BW) - 1;
+        self.mask.range_off(s_off, e_off);
+    }
+
+    unsafe fn add_free_block(&mut self, addr: u64, size: u64) {
+        // println!("add_free_block");
+        let (fl, sl) = size_class(size);
+        let list = &mut self.lists[fl * HEAP_SL_COUNT + sl];
+        let _addr = list.start_addr();
+        let block = FreeBlock {
+            size,
+            prev: Some(&mut *(_addr as *mut FreeBlock)),
+            next: list.next.take(),
+        };
+
+        let mut _ptr = addr as *mut FreeBlock;
+        _ptr.write(block);
+        list.next = Some(&mut *_ptr);
+        if let Some(ref mut next) = (*_ptr).next {
+            next.prev = Some(&mut *_ptr);
+        }
+        (&mut *_ptr).write_addr_at_end();
+        self.fl_bitmap |= 1 << fl;
+        self.sl_bitmap[fl] |= 1 << sl;
+    }
+
+    /// takes the free block at `addr` off its list, its size must be unchanged
+    unsafe fn remove_free_block(&mut self, addr: u64) {
+        let block = &mut *(addr as *mut FreeBlock);
+        let (fl, sl) = size_class(block.size);
+        block.release();
+        if self.lists[fl * HEAP_SL_COUNT + sl].next.is_none() {
+            self.sl_bitmap[fl] &= !(1 << sl);
+            if self.sl_bitmap[fl] == 0 {
+                self.fl_bitmap &= !(1 << fl);
+            }
+        }
+    }
+
+    unsafe fn expand_mask(&mut self) -> Result<(), MapToError<Size2MiB>> {
+        // println!("expand_mask");
+        let s_off = self.mask.size;
+        self.provider.map(self.mask.boudry_addr())?;
+        self.mask.size += 8 * FRAME_SIZE;
+        let e_off = self.mask.size-1;
+        self.mask.range_off(s_off, e_off);
+        Ok(())
+    }
+
+    /// the first non empty list at or above `(fl, sl)`
+    fn find_list(&self, fl: usize, sl: usize) -> Option<(usize, usize)> {
+        if fl >= HEAP_FL_COUNT {
+            return None;
+        }
+        let sl_map = self.sl_bitmap[fl] & (!0u8 << sl);
+        if sl_map != 0 {
+            return Some((fl, sl_map.trailing_zeros() as usize));
+        }
+        let fl_map = self.fl_bitmap & (!0u64 << (fl + 1));
+        if fl_map == 0 {
+            return None;
+        }
+        let fl = fl_map.trailing_zeros() as usize;
+        Some((fl, self.sl_bitmap[fl].trailing_zeros() as usize))
+    }
+
+    /// Finds a block of at least `size` bytes in constant time. The list
+    /// `size` falls into may also hold smaller blocks, so the search starts at
+    /// the first list above it. A block on that list which would fit is passed
+    /// over and the heap grows instead.
+    fn find_block(&self, size: u64) -> Option<u64> {
+        if size > HEAP_MAX_SIZE_LIMIT {
+            return None;
+        }
+        let (fl, sl) = search_class(size);
+        self.find_list(fl, sl).and_then(|(fl, sl)| {
+            self.lists[fl * HEAP_SL_COUNT + sl]
+                .next
+                .as_ref()
+                .map(|block| block.start_addr())
+        })
+    }
+
+    /// returns null when the heap can not grow enough to fit `size`
+    unsafe fn find_and_alloc(&mut self, size: u64) -> *mut u8 {
+        // println!("find_and_alloc");
+        let _addr = match self.find_block(size) {
+            Some(_addr) => _addr,
+            None => {
+                // a failed expand may still have mapped enough for this request,
+                // it has to reach the list `find_block` searches
+                let _ = self.expand(search_size(size));
+                match self.find_block(size) {
+                    Some(_addr) => _addr,
+                    None => {
+                        self.failed_allocs += 1;
+                        return ptr::null_mut();
+                    }
+                }
+            }
+        };
+
+        // hand out the end of the block, whatever is left keeps its start
+        let _block_size = (*(_addr as *const FreeBlock)).size;
+        self.remove_free_block(_addr);
+        if _block_size > size {
+            self.add_free_block(_addr, _block_size - size);
+        }
+        let _ptr = (_addr + _block_size - size) as *mut u8;
+
+        let _addr_1 = _ptr as u64;
+        let s_off = (_addr_1 - self.heap_start) >> HEAP_BLOCK_SIZE_BW;
+        let e_off = ((_addr_1 + size - self.heap_start) >> HEAP_BLOCK_SIZE_BW) - 1;
+        self.mask.range_on(s_off, e_off);
+
+        self.note_in_use(size);
+        self.live_allocs += 1;
+        self.total_allocs += 1;
+        // println!("find_and_alloc end");
+        _ptr
+    }
+
+    fn note_in_use(&mut self, size: u64) {
+        self.in_use += size;
+        if self.in_use > self.peak_in_use {
+            self.peak_in_use = self.in_use;
+        }
+    }
+
+    fn stats(&self) -> HeapStats {
+        let mut stats = HeapStats {
+            heap_size: self.size,
+            mapped: self.mapped_size(),
+            in_use: self.in_use,
+            peak_in_use: self.peak_in_use,
+            live_allocs: self.live_allocs,
+            total_allocs: self.total_allocs,
+            total_frees: self.total_frees,
+            failed_allocs: self.failed_allocs,
+            free_blocks: 0,
+            free_bytes: 0,
+            largest_free: 0,
+            free_histogram: [0; HEAP_HISTOGRAM_BUCKETS],
+        };
+
+        for list in self.lists.iter() {
+            let mut curr = list.next.as_ref();
+            while let Some(block) = curr {
+                stats.free_blocks += 1;
+                stats.free_bytes += block.size;
+                stats.largest_free = core::cmp::max(stats.largest_free, block.size);
+                let bucket = (63 - block.size.leading_zeros() as u64).saturating_sub(HEAP_BLOCK_SIZE_BW);
+                stats.free_histogram[core::cmp::min(bucket as usize, HEAP_HISTOGRAM_BUCKETS - 1)] += 1;
+                curr = block.next.as_ref();
+            }
+        }
+        stats
+    }
+
+    /// Resizes the allocation at `addr` without moving it: shrinking hands the
+    /// tail back to the free list, growing takes the front of the free block
+    /// right behind it. Returns false, changing nothing, when that neighbour is
+    /// in use or too small.
+    unsafe fn resize_in_place(&mut self, addr: u64, old_size: u64, new_size: u64) -> bool {
+        if new_size == old_size {
+            return true;
+        }
+        if new_size < old_size {
+            self.in_use -= old_size - new_size;
+            self.free_block(addr + new_size, old_size - new_size);
+            return true;
+        }
+
+        let extra = new_size - old_size;
+        let n_addr = addr + old_size;
+        let n_off = (n_addr - self.heap_start) >> HEAP_BLOCK_SIZE_BW;
+        if n_off >= self.size >> HEAP_BLOCK_SIZE_BW || self.mask.is_set(n_off) {
+            return false;
+        }
+
+        let next_size = (*(n_addr as *const FreeBlock)).size;
+        if next_size < extra {
+            return false;
+        }
+        self.remove_free_block(n_addr);
+        if next_size > extra {
+            self.add_free_block(n_addr + extra, next_size - extra);
+        }
+        self.mask_on(n_addr, extra);
+        self.note_in_use(extra);
+        true
+    }
+
+}
+
+fn size_align(layout: Layout) -> (u64, u64) {
+    let size = ((layout.size() + 63) >> HEAP_BLOCK_SIZE_BW << HEAP_BLOCK_SIZE_BW) as u64;
+    #[cfg(feature = "heap_debug")]
+    let size = size + 2 * debug::REDZONE;
+    (size, 8)
+}
+
+/// first and second level index of the free list holding blocks of `size`
+fn size_class(size: u64) -> (usize, usize) {
+    let fl = 63 - size.leading_zeros() as u64;
+    let sl = (size >> (fl - HEAP_SL_BW)) & (HEAP_SL_COUNT as u64 - 1);
+    ((fl - HEAP_BLOCK_SIZE_BW) as usize, sl as usize)
+}
+
+/// `size` rounded up to the smallest size of a free list
+fn search_size(size: u64) -> u64 {
+    let fl = 63 - size.leading_zeros() as u64;
+    let step = 1 << (fl - HEAP_SL_BW);
+    (size + step - 1) & !(step - 1)
+}
+
+/// the first free list whose blocks are all at least `size` bytes
+fn search_class(size: u64) -> (usize, usize) {
+    size_class(search_size(size))
+}
+
+fn frame_align_up(size: u64) -> u64 {
+    (size + FRAME_SIZE - 1) >> FRAME_SIZE_BIT_WIDTH << FRAME_SIZE_BIT_WIDTH
+}
+
+fn is_large(addr: u64) -> bool {
+    addr >= HEAP_LARGE_START_ADDR && addr < HEAP_LARGE_START_ADDR + HEAP_LARGE_SLOTS * FRAME_SIZE
+}
+
+/// Large allocations skip the heap_debug redzones, freeing unmaps them and a
+/// use after free faults anyway.
+unsafe fn alloc_large(size: u64) -> *mut u8 {
+    let mut p = LARGE.lock().alloc(size);
+    if p.is_null() {
+        p = oom::retry(|| LARGE.lock().alloc(size));
+    }
+    track::record(p as u64, frame_align_up(size));
+    p
+}
+
+unsafe impl GlobalAlloc for IrqLocked<HeapAllocator<FrameProvider>> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        // println!("alloc");
+        if layout.size() as u64 >= HEAP_LARGE_THRESHOLD {
+            return alloc_large(layout.size() as u64);
+        }
+        let (size, _) = size_align(layout);
+        let mut p = self.lock().find_and_alloc(size);
+        if p.is_null() {
+            p = oom::retry(|| self.lock().find_and_alloc(size));
+        }
+        #[cfg(feature = "heap_debug")]
+        let p = debug::arm(p, layout, size);
+        track::record(p as u64, size);
+
+        // println!("alloc end");
+        p
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        // println!("dealloc");
+        track::forget(ptr as u64);
+        if is_large(ptr as u64) {
+            LARGE.lock().dealloc(ptr as u64, layout.size() as u64);
+            return;
+        }
+        let (size, _) = size_align(layout);
+        let mut heap = self.lock();
+        #[cfg(feature = "heap_debug")]
+        let ptr = match debug::check(&mut heap, ptr, layout, size) {
+            Some(ptr) => ptr,
+            None => return,
+        };
+        heap.dealloc(ptr as u64, size);
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let new_layout = Layout::from_size_align_unchecked(new_size, layout.align());
+        let large = is_large(ptr as u64);
+        if large && new_size as u64 >= HEAP_LARGE_THRESHOLD {
+            if LARGE.lock().resize_in_place(ptr as u64, layout.size() as u64, new_size as u64) {
+                track::record(ptr as u64, frame_align_up(new_size as u64));
+                return ptr;
+            }
+        } else if !large && (new_size as u64) < HEAP_LARGE_THRESHOLD {
+            // the redzones would have to move along with the end of the allocation
+            #[cfg(not(feature = "heap_debug"))]
+            {
+                let (old_size, _) = size_align(layout);
+                let (size, _) = size_align(new_layout);
+                if self.lock().resize_in_place(ptr as u64, old_size, size) {
+                    track::record(ptr as u64, size);
+                    return ptr;
+                }
+            }
+        }
+
+        let new_ptr = self.alloc(new_layout);
+        if !new_ptr.is_null() {
+            ptr::copy_nonoverlapping(ptr, new_ptr, core::cmp::min(layout.size(), new_size));
+            self.dealloc(ptr, layout);
+        }
+        new_ptr
+    }
+}
+
+/// Sets how many bytes the heap keeps mapped before freed frames are
+/// returned to the frame allocator.
+pub fn set_low_water_mark(size: u64) {
+    ALLOCATOR.lock().low_water = size;
+}
+
+/// Sets the smallest step, in bytes, the heap grows by when it runs out of space.
+pub fn set_min_increment(size: u64) {
+    ALLOCATOR.lock().min_increment = frame_align_up(size);
+}
+
+/// Sets the largest size, in bytes, the heap may grow to. Allocations which
+/// would need more than that fail instead of mapping further frames.
+pub fn set_max_size(size: u64) {
+    ALLOCATOR.lock().max_size = core::cmp::min(frame_align_up(size), HEAP_MAX_SIZE_LIMIT);
+}
+
+pub fn stats() -> HeapStats {
+    ALLOCATOR.lock().stats()
+}
+
+pub fn large_stats() -> LargeStats {
+    LARGE.lock().stats()
+}
+
+/// Prints the heap counters and a histogram of free block sizes, used by the
+/// `heap` console command.
+pub fn print_stats() {
+    let s = stats();
+    let fragmentation = if s.free_bytes > 0 {
+        100 - s.largest_free * 100 / s.free_bytes
+    } else {
+        0
+    };
+
+    println!(
+        "heap: {}K mapped of {}K, {}K in use, peak {}K",
+        s.mapped >> 10,
+        s.heap_size >> 10,
+        s.in_use >> 10,
+        s.peak_in_use >> 10
+    );
+    println!(
+        "allocs: {} live, {} total, {} freed, {} failed",
+        s.live_allocs, s.total_allocs, s.total_frees, s.failed_allocs
+    );
+    println!(
+        "free: {} blocks, {}K, largest {}K, fragmentation {}%",
+        s.free_blocks,
+        s.free_bytes >> 10,
+        s.largest_free >> 10,
+        fragmentation
+    );
+    for (i, n) in s.free_histogram.iter().enumerate() {
+        if *n > 0 {
+            println!("  >= {:>8}B: {}", HEAP_BLOCK_SIZE << i, n);
+        }
+    }
+
+    let l = large_stats();
+    println!(
+        "large: {}K mapped, peak {}K, {} live, {} total, {} failed",
+        l.mapped >> 10,
+        l.peak_mapped >> 10,
+        l.live_allocs,
+        l.total_allocs,
+        l.failed_allocs
+    );
+}
+
+pub fn init() {
+    unsafe {
+        let mut alloc = ALLOCATOR.lock();
+        alloc.expand(HEAP_MIN_INCREMENT).expect("failed to map the initial heap");
+    }
+}
@@ -0,0 +1,722 @@
+//! Host side tests for the heap, run with `make test`. The heap and its mask
+//! live in a `Vec` backed arena instead of frames from `FRAME_ALLOC`.
+
+use super::*;
+use crate::memory::frame_controller::tests::FRAME_ALLOC_TEST;
+use crate::util::test_interrupts;
+use alloc::boxed::Box;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU64, Ordering};
+use x86_64::structures::paging::PhysFrame;
+
+const ARENA_FRAMES: u64 = 16;
+
+/// Hands out frames of a `Vec`, the first one holds the mask. Frames are
+/// filled with garbage when mapped and unmapped, so the heap can not rely
+/// on their content.
+struct VecProvider {
+    _arena: Vec<u8>,
+    start: u64,
+    mapped: Vec<bool>,
+}
+
+impl VecProvider {
+    fn new(frames: u64) -> Self {
+        let arena = vec![0u8; ((frames + 1) * FRAME_SIZE) as usize];
+        let start = frame_align_up(arena.as_ptr() as u64);
+        VecProvider {
+            _arena: arena,
+            start,
+            mapped: vec![false; frames as usize],
+        }
+    }
+
+    fn frame(&self, addr: u64) -> Option<usize> {
+        if addr < self.start || addr & (FRAME_SIZE - 1) != 0 {
+            return None;
+        }
+        let i = ((addr - self.start) >> FRAME_SIZE_BIT_WIDTH) as usize;
+        if i < self.mapped.len() {
+            Some(i)
+        } else {
+            None
+        }
+    }
+
+    fn mapped_frames(&self) -> u64 {
+        self.mapped.iter().filter(|m| **m).count() as u64
+    }
+}
+
+impl HeapProvider for VecProvider {
+    fn map(&mut self, addr: u64) -> Result<(), MapToError<Size2MiB>> {
+        let i = self.frame(addr).ok_or(MapToError::FrameAllocationFailed)?;
+        assert!(!self.mapped[i], "frame 0x{:x} mapped twice", addr);
+        self.mapped[i] = true;
+        unsafe { ptr::write_bytes(addr as *mut u8, 0x5a, FRAME_SIZE as usize) };
+        Ok(())
+    }
+
+    fn unmap(&mut self, addr: u64) {
+        let i = self.frame(addr).expect("unmap outside of the arena");
+        assert!(self.mapped[i], "frame 0x{:x} unmapped twice", addr);
+        self.mapped[i] = false;
+        unsafe { ptr::write_bytes(addr as *mut u8, 0xa5, FRAME_SIZE as usize) };
+    }
+}
+
+/// Takes frames from `FRAME_ALLOC` and gives them back like `FrameProvider`,
+/// but maps nothing, for allocators which never touch their memory.
+struct FrameAllocProvider {
+    frames: Vec<(u64, PhysFrame<Size2MiB>)>,
+}
+
+impl HeapProvider for FrameAllocProvider {
+    fn map(&mut self, addr: u64) -> Result<(), MapToError<Size2MiB>> {
+        let frame = FRAME_ALLOC
+            .lock()
+            .allocate()
+            .ok_or(MapToError::FrameAllocationFailed)?;
+        self.frames.push((addr, frame.frame()));
+        Ok(())
+    }
+
+    fn unmap(&mut self, addr: u64) {
+        let i = self.frames.iter().position(|f| f.0 == addr).expect("unmap of a frame never mapped");
+        FRAME_ALLOC.lock().deallocate(self.frames.swap_remove(i).1);
+    }
+}
+
+fn new_heap(frames: u64) -> Box<HeapAllocator<VecProvider>> {
+    let provider = VecProvider::new(frames + 1);
+    let mask_start = provider.start;
+    let mut heap = Box::new(HeapAllocator::new(mask_start + FRAME_SIZE, mask_start, provider));
+    heap.min_increment = FRAME_SIZE;
+    heap.max_size = frames * FRAME_SIZE;
+    heap.low_water = FRAME_SIZE;
+    heap
+}
+
+fn round(size: u64) -> u64 {
+    (size + HEAP_BLOCK_SIZE - 1) & !(HEAP_BLOCK_SIZE - 1)
+}
+
+fn offset(heap: &HeapAllocator<VecProvider>, addr: u64) -> u64 {
+    (addr - heap.heap_start) >> HEAP_BLOCK_SIZE_BW
+}
+
+/// Checks the free list links, that every free block sits in the list of its
+/// size and the bitmaps agree, the end tags, that free blocks, `live`
+/// allocations and released holes tile the heap without gaps or overlaps,
+/// that the mask agrees, that free blocks are fully merged and that the
+/// counters add up.
+unsafe fn check_heap(heap: &HeapAllocator<VecProvider>, live: &[(u64, u64)]) {
+    let mut ranges = Vec::new();
+
+    for (i, list) in heap.lists.iter().enumerate() {
+        let (fl, sl) = (i / HEAP_SL_COUNT, i % HEAP_SL_COUNT);
+        assert_eq!(
+            heap.sl_bitmap[fl] & (1 << sl) != 0,
+            list.next.is_some(),
+            "bitmap out of sync for list ({}, {})",
+            fl,
+            sl
+        );
+        assert_eq!(heap.fl_bitmap & (1 << fl) != 0, heap.sl_bitmap[fl] != 0);
+
+        let mut prev = list.start_addr();
+        let mut curr = list.next.as_ref();
+        while let Some(block) = curr {
+            let (s, e) = (block.start_addr(), block.end_addr());
+            assert_eq!(
+                block.prev.as_ref().map(|p| p.start_addr()),
+                Some(prev),
+                "broken prev link at 0x{:x}",
+                s
+            );
+            assert!(block.size > 0 && block.size % HEAP_BLOCK_SIZE == 0);
+            assert_eq!(size_class(block.size), (fl, sl), "block 0x{:x} in the wrong list", s);
+            assert!(s >= heap.heap_start && e <= heap.boundry_addr());
+            assert_eq!(((e - 8) as *const u64).read(), s, "stale end tag at 0x{:x}", s);
+            for off in offset(heap, s)..offset(heap, e) {
+                assert!(!heap.mask.is_set(off), "free block 0x{:x} marked used", s);
+            }
+            ranges.push((s, e, 'f'));
+            prev = s;
+            curr = block.next.as_ref();
+        }
+    }
+
+    for &(addr, size) in live {
+        for off in offset(heap, addr)..offset(heap, addr + size) {
+            assert!(heap.mask.is_set(off), "allocation 0x{:x} marked free", addr);
+        }
+        ranges.push((addr, addr + size, 'a'));
+    }
+    for hole in &heap.holes[..heap.n_holes] {
+        ranges.push((hole.addr, hole.addr + hole.size, 'h'));
+    }
+
+    ranges.sort();
+    let mut at = heap.heap_start;
+    let mut last = ' ';
+    for (s, e, kind) in ranges {
+        assert_eq!(s, at, "gap or overlap at 0x{:x}", s);
+        assert!(!(kind == 'f' && last == 'f'), "unmerged free blocks at 0x{:x}", s);
+        at = e;
+        last = kind;
+    }
+    assert_eq!(at, heap.boundry_addr());
+
+    assert_eq!(heap.in_use, live.iter().map(|l| l.1).sum::<u64>());
+    assert_eq!(heap.live_allocs, live.len() as u64);
+    let released: u64 = heap.holes[..heap.n_holes].iter().map(|h| h.size).sum();
+    assert_eq!(heap.released, released);
+    assert_eq!(heap.provider.mapped_frames() - 1, heap.mapped_size() >> FRAME_SIZE_BIT_WIDTH);
+}
+
+unsafe fn fill(addr: u64, size: u64, byte: u8) {
+    ptr::write_bytes(addr as *mut u8, byte, size as usize);
+}
+
+unsafe fn verify(addr: u64, size: u64, byte: u8) {
+    let data = core::slice::from_raw_parts(addr as *const u8, size as usize);
+    assert!(
+        data.iter().all(|b| *b == byte),
+        "allocation 0x{:x} was overwritten",
+        addr
+    );
+}
+
+struct XorShift(u64);
+
+impl XorShift {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    fn below(&mut self, n: u64) -> u64 {
+        self.next() % n
+    }
+
+    /// mostly small sizes, now and then a few hundred kilobytes
+    fn size(&mut self) -> u64 {
+        match self.below(16) {
+            0 => self.below(512 * 1024) + 1,
+            1..=4 => self.below(8192) + 1,
+            _ => self.below(512) + 1,
+        }
+    }
+}
+
+#[test]
+fn mask_ranges_across_words() {
+    let mut heap = new_heap(2);
+    unsafe {
+        heap.expand(FRAME_SIZE).unwrap();
+        heap.mask.range_on(10, 200);
+        for i in 0..300 {
+            assert_eq!(heap.mask.is_set(i), (10..=200).contains(&i), "bit {}", i);
+        }
+        heap.mask.range_off(60, 130);
+        for i in 0..300 {
+            let on = (10..60).contains(&i) || (131..=200).contains(&i);
+            assert_eq!(heap.mask.is_set(i), on, "bit {}", i);
+        }
+    }
+}
+
+#[test]
+fn free_merges_with_both_neighbours() {
+    let mut heap = new_heap(2);
+    unsafe {
+        let a = heap.find_and_alloc(128) as u64;
+        let b = heap.find_and_alloc(256) as u64;
+        let c = heap.find_and_alloc(64) as u64;
+        check_heap(&heap, &[(a, 128), (b, 256), (c, 64)]);
+
+        heap.dealloc(a, 128);
+        heap.dealloc(c, 64);
+        check_heap(&heap, &[(b, 256)]);
+        heap.dealloc(b, 256);
+        check_heap(&heap, &[]);
+        assert_eq!(heap.stats().free_blocks, 1);
+    }
+}
+
+#[test]
+fn free_merges_with_the_block_behind() {
+    let mut heap = new_heap(2);
+    unsafe {
+        // `b` ends where `a` starts and `c` keeps it from merging downwards
+        let a = heap.find_and_alloc(128) as u64;
+        let b = heap.find_and_alloc(256) as u64;
+        let c = heap.find_and_alloc(64) as u64;
+        assert_eq!(b + 256, a);
+
+        heap.dealloc(a, 128);
+        check_heap(&heap, &[(b, 256), (c, 64)]);
+        heap.dealloc(b, 256);
+        check_heap(&heap, &[(c, 64)]);
+    }
+}
+
+#[test]
+fn realloc_grows_and_shrinks_in_place() {
+    let mut heap = new_heap(2);
+    unsafe {
+        // blocks are handed out from the top down, `b` ends where `a` starts
+        let a = heap.find_and_alloc(1024) as u64;
+        let b = heap.find_and_alloc(128) as u64;
+        assert_eq!(b + 128, a);
+        heap.dealloc(a, 1024);
+
+        assert!(heap.resize_in_place(b, 128, 512));
+        check_heap(&heap, &[(b, 512)]);
+        assert!(heap.resize_in_place(b, 512, 128));
+        check_heap(&heap, &[(b, 128)]);
+
+        // take everything behind `b`, it can't grow any more
+        let c = heap.find_and_alloc(1024) as u64;
+        assert_eq!(c, b + 128);
+        assert!(!heap.resize_in_place(b, 128, 192));
+        check_heap(&heap, &[(b, 128), (c, 1024)]);
+    }
+}
+
+#[test]
+fn find_block_rounds_up_to_the_next_list() {
+    let mut heap = new_heap(1);
+    unsafe {
+        // leave one free block of 1088 bytes, on the list for 1024 to 1151
+        let a = heap.find_and_alloc(FRAME_SIZE - 1088) as u64;
+        assert_ne!(a, 0);
+        assert_eq!(search_size(1088), 1152);
+
+        // it would fit, but only blocks of 1152 bytes and up are searched
+        assert!(heap.find_and_alloc(1088).is_null());
+        let b = heap.find_and_alloc(1024) as u64;
+        assert_ne!(b, 0);
+        check_heap(&heap, &[(a, FRAME_SIZE - 1088), (b, 1024)]);
+    }
+}
+
+#[test]
+fn expand_stops_at_max_size() {
+    let mut heap = new_heap(4);
+    unsafe {
+        let a = heap.find_and_alloc(3 * FRAME_SIZE) as u64;
+        assert_ne!(a, 0);
+        assert!(heap.find_and_alloc(2 * FRAME_SIZE).is_null());
+        assert_eq!(heap.failed_allocs, 1);
+        check_heap(&heap, &[(a, 3 * FRAME_SIZE)]);
+    }
+}
+
+#[test]
+fn free_frames_go_back_above_low_water() {
+    let mut heap = new_heap(8);
+    unsafe {
+        let a = heap.find_and_alloc(6 * FRAME_SIZE) as u64;
+        let b = heap.find_and_alloc(64) as u64;
+        check_heap(&heap, &[(a, 6 * FRAME_SIZE), (b, 64)]);
+
+        heap.dealloc(a, 6 * FRAME_SIZE);
+        check_heap(&heap, &[(b, 64)]);
+        assert!(heap.released > 0);
+        assert!(heap.mapped_size() >= heap.low_water);
+
+        let c = heap.find_and_alloc(5 * FRAME_SIZE) as u64;
+        assert_ne!(c, 0);
+        check_heap(&heap, &[(b, 64), (c, 5 * FRAME_SIZE)]);
+    }
+}
+
+#[cfg(feature = "heap_debug")]
+#[test]
+fn double_free_in_released_hole_is_ignored() {
+    let mut heap = new_heap(8);
+    let small = Layout::from_size_align(64, 8).unwrap();
+    let big = Layout::from_size_align(5 * FRAME_SIZE as usize, 8).unwrap();
+    let (small_size, _) = size_align(small);
+    let (big_size, _) = size_align(big);
+    unsafe {
+        let a = debug::arm(heap.find_and_alloc(big_size), big, big_size);
+        let x = debug::arm(heap.find_and_alloc(small_size), small, small_size);
+        let b = heap.find_and_alloc(FRAME_SIZE) as u64;
+        let x_block = debug::check(&mut heap, x, small, small_size).unwrap();
+        heap.dealloc(x_block as u64, small_size);
+        let a_block = debug::check(&mut heap, a, big, big_size).unwrap();
+        heap.dealloc(a_block as u64, big_size);
+        check_heap(&heap, &[(b, FRAME_SIZE)]);
+
+        // x now lies in a hole, unmapped but still set in the mask
+        let hole = heap.holes[..heap.n_holes]
+            .iter()
+            .copied()
+            .find(|h| h.addr <= x_block as u64 && x_block as u64 + small_size <= h.addr + h.size)
+            .expect("x was not released");
+        assert!(debug::check(&mut heap, x, small, small_size).is_none());
+        verify(hole.addr, hole.size, 0xa5);
+        check_heap(&heap, &[(b, FRAME_SIZE)]);
+    }
+}
+
+unsafe fn fuzz(seed: u64, rounds: usize) {
+    let mut heap = new_heap(ARENA_FRAMES);
+    let mut rng = XorShift(seed.wrapping_mul(0x9e37_79b9_7f4a_7c15) | 1);
+    let mut live: Vec<(u64, u64)> = Vec::new();
+    let mut fills: Vec<u8> = Vec::new();
+
+    for n in 0..rounds {
+        match rng.below(4) {
+            0 | 1 if !live.is_empty() => {
+                let i = rng.below(live.len() as u64) as usize;
+                let (addr, size) = live.swap_remove(i);
+                verify(addr, size, fills.swap_remove(i));
+                heap.dealloc(addr, size);
+            }
+            2 if !live.is_empty() => {
+                let i = rng.below(live.len() as u64) as usize;
+                let (addr, size) = live[i];
+                let new_size = round(rng.size());
+                verify(addr, size, fills[i]);
+                if heap.resize_in_place(addr, size, new_size) {
+                    verify(addr, core::cmp::min(size, new_size), fills[i]);
+                    live[i].1 = new_size;
+                } else {
+                    let p = heap.find_and_alloc(new_size) as u64;
+                    if p == 0 {
+                        continue;
+                    }
+                    ptr::copy_nonoverlapping(addr as *const u8, p as *mut u8, size as usize);
+                    heap.dealloc(addr, size);
+                    verify(p, size, fills[i]);
+                    live[i] = (p, new_size);
+                }
+                fill(live[i].0, live[i].1, fills[i]);
+            }
+            _ => {
+                let size = round(rng.size());
+                let p = heap.find_and_alloc(size) as u64;
+                if p == 0 {
+                    continue;
+                }
+                let byte = (rng.next() & 0xff) as u8;
+                fill(p, size, byte);
+                live.push((p, size));
+                fills.push(byte);
+            }
+        }
+        if n % 64 == 0 {
+            check_heap(&heap, &live);
+        }
+    }
+
+    check_heap(&heap, &live);
+    for (i, (addr, size)) in live.drain(..).enumerate() {
+        verify(addr, size, fills[i]);
+        heap.dealloc(addr, size);
+    }
+    check_heap(&heap, &[]);
+    assert!(heap.mapped_size() >= heap.low_water);
+}
+
+#[test]
+fn fuzz_alloc_free_realloc() {
+    for seed in 1..=16 {
+        unsafe { fuzz(seed, 5000) };
+    }
+}
+
+static IRQ_HEAP: IrqLocked<Option<Box<HeapAllocator<VecProvider>>>> = IrqLocked::new(None);
+static IRQ_ALLOCS: AtomicU64 = AtomicU64::new(0);
+
+/// A timer handler which allocates, fills and frees on every tick.
+fn timer_alloc() {
+    let mut guard = IRQ_HEAP.lock();
+    let heap = guard.as_mut().unwrap();
+    unsafe {
+        let p = heap.find_and_alloc(64 * 8) as *mut u64;
+        assert!(!p.is_null());
+        for j in 0..64 {
+            p.add(j).write(j as u64);
+        }
+        assert_eq!(p.add(63).read(), 63);
+        heap.dealloc(p as u64, 64 * 8);
+    }
+    IRQ_ALLOCS.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Ticks come in while the interrupted code holds the heap lock and while it
+/// doesn't. If the lock let the handler in, it would spin on the lock its
+/// own CPU holds and the test would hang.
+#[test]
+fn irq_handler_allocates_under_load() {
+    *IRQ_HEAP.lock() = Some(new_heap(4));
+    let mut rng = XorShift(0x0dd_b1a5_e5);
+    let mut live: Vec<(u64, u64, u8)> = Vec::new();
+    let mut ticks = 0;
+
+    for _ in 0..20000 {
+        let mut guard = IRQ_HEAP.lock();
+        let heap = guard.as_mut().unwrap();
+        if live.len() >= 500 || (!live.is_empty() && rng.below(3) == 0) {
+            let i = rng.below(live.len() as u64) as usize;
+            let (addr, size, byte) = live.swap_remove(i);
+            unsafe {
+                verify(addr, size, byte);
+                heap.dealloc(addr, size);
+            }
+        } else {
+            let size = round(rng.below(2048) + 1);
+            let p = unsafe { heap.find_and_alloc(size) } as u64;
+            assert_ne!(p, 0);
+            let byte = (rng.next() & 0xff) as u8;
+            unsafe { fill(p, size, byte) };
+            live.push((p, size, byte));
+        }
+        if rng.below(4) == 0 {
+            test_interrupts::raise(timer_alloc);
+            ticks += 1;
+        }
+        drop(guard);
+        if rng.below(4) == 0 {
+            test_interrupts::raise(timer_alloc);
+            ticks += 1;
+        }
+    }
+
+    assert_eq!(IRQ_ALLOCS.load(Ordering::Relaxed), ticks);
+    let heap = IRQ_HEAP.lock().take().unwrap();
+    let live: Vec<(u64, u64)> = live.iter().map(|l| (l.0, l.1)).collect();
+    unsafe { check_heap(&heap, &live) };
+}
+
+/// where the link of `SingleList` sits in a free block, behind the header and
+/// in front of the end tag of even the smallest block
+const LINK_OFFSET: u64 = core::mem::size_of::<FreeBlock>() as u64;
+
+/// The free blocks on one list, the way the heap kept them before the lists
+/// were segregated by size. Freeing pushed a block on the head of the list,
+/// so after frees going from the top of the heap down the list runs by
+/// ascending address. Each block links to the next one right behind its
+/// header, so a walk reads the same memory the old one did.
+struct SingleList {
+    head: u64,
+}
+
+impl SingleList {
+    unsafe fn new(heap: &HeapAllocator<VecProvider>) -> Self {
+        assert!(LINK_OFFSET + 8 <= HEAP_BLOCK_SIZE - 8);
+        let mut blocks = Vec::new();
+        for list in heap.lists.iter() {
+            let mut curr = list.next.as_ref();
+            while let Some(block) = curr {
+                blocks.push(block.start_addr());
+                curr = block.next.as_ref();
+            }
+        }
+        blocks.sort();
+        let mut head = 0;
+        for &addr in blocks.iter().rev() {
+            ((addr + LINK_OFFSET) as *mut u64).write(head);
+            head = addr;
+        }
+        SingleList { head }
+    }
+
+    /// the first block which fits, like `find_block` on the single list
+    unsafe fn first_fit(&self, size: u64) -> Option<u64> {
+        let mut curr = self.head;
+        while curr != 0 {
+            if (*(curr as *const FreeBlock)).size >= size {
+                return Some(curr);
+            }
+            curr = ((curr + LINK_OFFSET) as *const u64).read();
+        }
+        None
+    }
+}
+
+/// Compares block lookup on a badly fragmented heap against the first fit
+/// walk of the single free list the heap had before. Run with `cargo test --release -- --ignored --nocapture`.
+#[test]
+#[ignore]
+fn bench_find_block() {
+    use std::time::Instant;
+
+    const LOOKUPS: usize = 10000;
+    let mut heap = new_heap(ARENA_FRAMES);
+    let mut rng = XorShift(0x2545_f491_4f6c_dd1d);
+    unsafe {
+        // fill the heap, then free every other allocation so nothing merges
+        let mut live = Vec::new();
+        loop {
+            let size = round(rng.below(2048) + 1);
+            let p = heap.find_and_alloc(size) as u64;
+            if p == 0 {
+                break;
+            }
+            live.push((p, size));
+        }
+        for &(addr, size) in live.iter().step_by(2) {
+            heap.dealloc(addr, size);
+        }
+        let sizes: Vec<u64> = (0..LOOKUPS).map(|_| round(rng.below(4096) + 1)).collect();
+
+        let mut found = 0;
+        let start = Instant::now();
+        for &size in &sizes {
+            found += heap.find_block(size).is_some() as usize;
+        }
+        let segregated = start.elapsed();
+
+        let single = SingleList::new(&heap);
+        let mut found_first_fit = 0;
+        let start = Instant::now();
+        for &size in &sizes {
+            found_first_fit += single.first_fit(size).is_some() as usize;
+        }
+        let linear = start.elapsed();
+
+        // requests are rounded up to the next list, some blocks which would fit are passed over
+        assert!(found <= found_first_fit);
+        std::println!(
+            "{} free blocks, {} lookups: segregated {:?}/op, single list {:?}/op",
+            heap.stats().free_blocks,
+            LOOKUPS,
+            segregated / LOOKUPS as u32,
+            linear / LOOKUPS as u32
+        );
+
+        let start = Instant::now();
+        for &size in &sizes {
+            let p = heap.find_and_alloc(size) as u64;
+            if p != 0 {
+                heap.dealloc(p, size);
+            }
+        }
+        std::println!("alloc and free: {:?}/op", start.elapsed() / LOOKUPS as u32);
+    }
+}
+
+#[test]
+fn track_table_finds_entries_after_removals() {
+    use track::{Entry, Tracker, EMPTY_ENTRY, TRACK_CAPACITY, TRACK_DEPTH};
+
+    let mut tracker = Tracker::new();
+    tracker.table = Some(vec![EMPTY_ENTRY; TRACK_CAPACITY].into_boxed_slice());
+    let mut rng = XorShift(0x1234_5678);
+    let mut live: Vec<u64> = Vec::new();
+    for _ in 0..20000 {
+        if !live.is_empty() && rng.below(3) == 0 {
+            let i = rng.below(live.len() as u64) as usize;
+            tracker.remove(live.swap_remove(i));
+        } else {
+            // clustered addresses, so probe sequences run into each other
+            let addr = HEAP_START_ADDR + (rng.below(1 << 16) << HEAP_BLOCK_SIZE_BW);
+            if !live.contains(&addr) {
+                live.push(addr);
+            }
+            tracker.insert(Entry {
+                addr,
+                size: 64,
+                frames: [addr; TRACK_DEPTH],
+            });
+        }
+    }
+
+    assert_eq!(tracker.live, live.len());
+    // removing only finds what the probe sequence still reaches
+    for addr in live {
+        tracker.remove(addr);
+    }
+    assert_eq!(tracker.live, 0);
+    assert!(tracker.table.as_ref().unwrap().iter().all(|e| e.addr == 0));
+}
+
+#[test]
+fn track_skips_allocator_frames() {
+    use track::is_plumbing;
+
+    // names as `tools/ksyms.sh` demangles them
+    for name in [
+        "<g8os::util::IrqLocked<g8os::memory::heap_allocator::HeapAllocator<g8os::memory::heap_allocator::FrameProvider>> as core::alloc::global::GlobalAlloc>::alloc",
+        "g8os::memory::heap_allocator::alloc_large",
+        "__rust_alloc",
+        "__rg_realloc",
+        "alloc::alloc::exchange_malloc",
+        "alloc::raw_vec::RawVec<T,A>::reserve::do_reserve_and_handle",
+        "<alloc::alloc::Global as core::alloc::Allocator>::allocate",
+        "<g8os::memory::arena::Arena as core::alloc::Allocator>::allocate",
+    ]
+    .iter()
+    {
+        assert!(is_plumbing(name), "{}", name);
+    }
+    for name in [
+        "g8os::task::sys_task::SysCmd::run",
+        "g8os::console::sys_log::_log",
+        "<g8os::task::executor::Executor>::spawn",
+    ]
+    .iter()
+    {
+        assert!(!is_plumbing(name), "{}", name);
+    }
+}
+
+#[test]
+fn large_allocations_map_whole_frames() {
+    let provider = VecProvider::new(6);
+    let start = provider.start;
+    let mut large = LargeAllocator::new(start, 6, provider);
+
+    let a = large.alloc(3 * FRAME_SIZE - 100) as u64;
+    let b = large.alloc(FRAME_SIZE) as u64;
+    assert_eq!((a, b), (start, start + 3 * FRAME_SIZE));
+    assert!(large.contains(b) && !large.contains(start + 6 * FRAME_SIZE));
+    assert_eq!(large.provider.mapped_frames(), 4);
+
+    // `b` sits right behind `a`
+    assert!(!large.resize_in_place(a, 3 * FRAME_SIZE - 100, 4 * FRAME_SIZE));
+    assert!(large.resize_in_place(a, 3 * FRAME_SIZE - 100, FRAME_SIZE));
+    assert_eq!(large.provider.mapped_frames(), 2);
+    assert!(large.resize_in_place(a, FRAME_SIZE, 2 * FRAME_SIZE));
+    assert_eq!(large.provider.mapped_frames(), 3);
+
+    assert!(large.alloc(3 * FRAME_SIZE).is_null());
+    let c = large.alloc(2 * FRAME_SIZE) as u64;
+    assert_eq!(c, start + 4 * FRAME_SIZE);
+
+    large.dealloc(a, 2 * FRAME_SIZE);
+    large.dealloc(b, FRAME_SIZE);
+    large.dealloc(c, 2 * FRAME_SIZE);
+    let s = large.stats();
+    assert_eq!((s.mapped, s.live_allocs, s.failed_allocs), (0, 0, 1));
+    assert_eq!(s.peak_mapped, 5 * FRAME_SIZE);
+    assert_eq!(large.provider.mapped_frames(), 0);
+}
+
+#[test]
+fn large_alloc_and_free_keep_free_frames_steady() {
+    let _serial = FRAME_ALLOC_TEST.lock();
+    let provider = FrameAllocProvider { frames: Vec::new() };
+    let mut large = LargeAllocator::new(HEAP_LARGE_START_ADDR, 8, provider);
+    let free = FRAME_ALLOC.lock().free_frames();
+
+    for i in 0..3000 {
+        let n = i % 3 + 1;
+        let a = large.alloc(n * FRAME_SIZE) as u64;
+        let b = large.alloc(FRAME_SIZE) as u64;
+        assert!(a != 0 && b != 0);
+        assert_eq!(FRAME_ALLOC.lock().free_frames(), free - n - 1);
+        large.dealloc(a, n * FRAME_SIZE);
+        large.dealloc(b, FRAME_SIZE);
+        assert_eq!(FRAME_ALLOC.lock().free_frames(), free);
+    }
+}
@@ -0,0 +1,305 @@
+//! Call-site tracking for live heap allocations, switched on and off at run
+//! time with `leaks on` / `leaks off`. While on, every allocation records its
+//! size and the return addresses of its callers in a table which is itself
+//! allocated when tracking starts, so nothing is recorded from inside the
+//! heap lock. `leaks` groups the live allocations by call site, `leaks snap`
+//! remembers the grouping and `leaks diff` prints what grew since.
+//!
+//! Needs the kernel built with frame pointers, see `x86_64-g8os.json`.
+
+use crate::backtrace;
+use crate::{print, println};
+use crate::util::IrqLocked;
+use alloc::boxed::Box;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+pub(super) const TRACK_CAPACITY: usize = 32768; // live allocations, 2M of table
+pub(super) const TRACK_DEPTH: usize = 6; // return addresses kept per allocation
+const TRACK_REPORT_SITES: usize = 16;
+
+static TRACKING: AtomicBool = AtomicBool::new(false);
+static TRACKER: IrqLocked<Tracker> = IrqLocked::new(Tracker::new());
+
+#[derive(Clone, Copy)]
+pub(super) struct Entry {
+    pub(super) addr: u64,
+    pub(super) size: u64,
+    pub(super) frames: [u64; TRACK_DEPTH],
+}
+
+pub(super) const EMPTY_ENTRY: Entry = Entry {
+    addr: 0,
+    size: 0,
+    frames: [0; TRACK_DEPTH],
+};
+
+/// live allocations grouped by the return addresses they were made from
+#[derive(Clone, Copy)]
+struct Site {
+    frames: [u64; TRACK_DEPTH],
+    count: u64,
+    bytes: u64,
+}
+
+pub(super) struct Tracker {
+    pub(super) table: Option<Box<[Entry]>>,
+    pub(super) live: usize,
+    dropped: u64,
+    snap: Option<Vec<Site>>,
+}
+
+impl Tracker {
+    pub(super) const fn new() -> Self {
+        Tracker {
+            table: None,
+            live: 0,
+            dropped: 0,
+            snap: None,
+        }
+    }
+
+    /// open addressing with linear probing, keyed by the allocation address
+    fn slot(addr: u64) -> usize {
+        (addr.wrapping_mul(0x9e37_79b9_7f4a_7c15) >> 49) as usize & (TRACK_CAPACITY - 1)
+    }
+
+    pub(super) fn insert(&mut self, entry: Entry) {
+        let table = match self.table.as_mut() {
+            Some(table) => table,
+            None => return,
+        };
+        let mut i = Self::slot(entry.addr);
+        loop {
+            if table[i].addr == entry.addr {
+                table[i] = entry;
+                return;
+            }
+            if table[i].addr == 0 {
+                break;
+            }
+            i = (i + 1) & (TRACK_CAPACITY - 1);
+        }
+        // keep a free slot, probing relies on hitting one
+        if self.live + 1 >= TRACK_CAPACITY {
+            self.dropped += 1;
+            return;
+        }
+        table[i] = entry;
+        self.live += 1;
+    }
+
+    pub(super) fn remove(&mut self, addr: u64) {
+        let table = match self.table.as_mut() {
+            Some(table) => table,
+            None => return,
+        };
+        let mut i = Self::slot(addr);
+        while table[i].addr != addr {
+            if table[i].addr == 0 {
+                return;
+            }
+            i = (i + 1) & (TRACK_CAPACITY - 1);
+        }
+
+        // shift the entries behind back, so no probe sequence gets cut short
+        let mut j = i;
+        loop {
+            j = (j + 1) & (TRACK_CAPACITY - 1);
+            if table[j].addr == 0 {
+                break;
+            }
+            let home = Self::slot(table[j].addr);
+            if (j.wrapping_sub(home) & (TRACK_CAPACITY - 1)) >= (j.wrapping_sub(i) & (TRACK_CAPACITY - 1)) {
+                table[i] = table[j];
+                i = j;
+            }
+        }
+        table[i] = EMPTY_ENTRY;
+        self.live -= 1;
+    }
+}
+
+/// Whether the function `name` is allocator plumbing: the global allocator,
+/// the arenas, the `__rust_alloc` shims or the `alloc` crate's collections.
+pub(super) fn is_plumbing(name: &str) -> bool {
+    name.starts_with("alloc::")
+        || name.starts_with("<alloc::")
+        || name.starts_with("__rust_")
+        || name.starts_with("__rg_")
+        || name.contains("heap_allocator")
+        || name.contains("memory::arena")
+}
+
+/// Up to `TRACK_DEPTH` return addresses, starting with the first caller
+/// outside the allocator plumbing. Without symbols only `record` is skipped.
+#[inline(never)]
+fn callers() -> [u64; TRACK_DEPTH] {
+    let mut frames = [0; TRACK_DEPTH];
+    // the first frame is `record`
+    let mut skip = 1;
+    let mut i = 0;
+    backtrace::walk(backtrace::current_rbp(), |ret| {
+        if skip > 0 {
+            skip -= 1;
+        } else if i == 0 && backtrace::symbolize(ret - 1).map_or(false, |(name, _)| is_plumbing(name)) {
+            // still inside the allocator
+        } else {
+            frames[i] = ret;
+            i += 1;
+        }
+        i < TRACK_DEPTH
+    });
+    frames
+}
+
+/// records the allocation at `addr`, called by the global allocator
+pub(super) fn record(addr: u64, size: u64) {
+    if !TRACKING.load(Ordering::Relaxed) || addr == 0 {
+        return;
+    }
+    let frames = callers();
+    TRACKER.lock().insert(Entry { addr, size, frames });
+}
+
+/// forgets the allocation at `addr`, called by the global allocator
+pub(super) fn forget(addr: u64) {
+    if !TRACKING.load(Ordering::Relaxed) {
+        return;
+    }
+    TRACKER.lock().remove(addr);
+}
+
+/// Starts tracking, allocations made before are not known.
+pub fn start() {
+    if TRACKING.load(Ordering::Relaxed) {
+        return;
+    }
+    let table = vec![EMPTY_ENTRY; TRACK_CAPACITY].into_boxed_slice();
+    let mut tracker = TRACKER.lock();
+    tracker.table = Some(table);
+    tracker.live = 0;
+    tracker.dropped = 0;
+    TRACKING.store(true, Ordering::Relaxed);
+}
+
+/// Stops tracking and drops the table and any snapshot.
+pub fn stop() {
+    TRACKING.store(false, Ordering::Relaxed);
+    // freed once the lock is gone
+    let (table, snap) = {
+        let mut tracker = TRACKER.lock();
+        (tracker.table.take(), tracker.snap.take())
+    };
+    drop(table);
+    drop(snap);
+}
+
+/// Live allocations grouped by call site, sorted by their return addresses.
+fn sites() -> Vec<Site> {
+    // allocating while holding the lock would deadlock on `record`
+    let mut sites = Vec::with_capacity(TRACK_CAPACITY);
+    {
+        let tracker = TRACKER.lock();
+        if let Some(table) = tracker.table.as_ref() {
+            for entry in table.iter().filter(|e| e.addr != 0) {
+                sites.push(Site {
+                    frames: entry.frames,
+                    count: 1,
+                    bytes: entry.size,
+                });
+            }
+        }
+    }
+
+    sites.sort_unstable_by(|a, b| a.frames.cmp(&b.frames));
+    sites.dedup_by(|s, group| {
+        if s.frames == group.frames {
+            group.count += s.count;
+            group.bytes += s.bytes;
+            true
+        } else {
+            false
+        }
+    });
+    sites
+}
+
+fn print_site(count: i64, bytes: i64, frames: &[u64; TRACK_DEPTH]) {
+    println!("{:>8}B in {:>5} at", bytes, count);
+    for ret in frames.iter().take_while(|r| **r != 0) {
+        print!("    ");
+        backtrace::print_addr(ret - 1);
+        println!();
+    }
+}
+
+/// `leaks`: the call sites holding the most memory
+pub fn report() {
+    if !TRACKING.load(Ordering::Relaxed) {
+        println!("leak tracking is off, start it with `leaks on`");
+        return;
+    }
+    let mut sites = sites();
+    sites.sort_unstable_by(|a, b| b.bytes.cmp(&a.bytes));
+    let (live, dropped) = {
+        let tracker = TRACKER.lock();
+        (tracker.live, tracker.dropped)
+    };
+    println!("{} live allocations from {} call sites, {} untracked", live, sites.len(), dropped);
+    for site in sites.iter().take(TRACK_REPORT_SITES) {
+        print_site(site.count as i64, site.bytes as i64, &site.frames);
+    }
+}
+
+/// `leaks snap`: remembers the current call sites for `leaks diff`
+pub fn snapshot() {
+    if !TRACKING.load(Ordering::Relaxed) {
+        println!("leak tracking is off, start it with `leaks on`");
+        return;
+    }
+    let mut sites = sites();
+    sites.shrink_to_fit();
+    let old = TRACKER.lock().snap.replace(sites);
+    drop(old);
+}
+
+/// `leaks diff`: the call sites which grew or shrank since `leaks snap`
+pub fn diff() {
+    let snap = TRACKER.lock().snap.take();
+    let snap = match snap {
+        Some(snap) => snap,
+        None => {
+            println!("no snapshot, take one with `leaks snap`");
+            return;
+        }
+    };
+
+    // both are sorted by call site
+    let now = sites();
+    let find = |sites: &[Site], frames: &[u64; TRACK_DEPTH]| {
+        sites
+            .binary_search_by(|s| s.frames.cmp(frames))
+            .map_or((0, 0), |i| (sites[i].count as i64, sites[i].bytes as i64))
+    };
+    let mut changes: Vec<(i64, i64, [u64; TRACK_DEPTH])> = Vec::new();
+    for site in now.iter() {
+        let (count, bytes) = find(&snap, &site.frames);
+        if site.count as i64 != count || site.bytes as i64 != bytes {
+            changes.push((site.count as i64 - count, site.bytes as i64 - bytes, site.frames));
+        }
+    }
+    for site in snap.iter() {
+        if find(&now, &site.frames) == (0, 0) {
+            changes.push((-(site.count as i64), -(site.bytes as i64), site.frames));
+        }
+    }
+    changes.sort_unstable_by(|a, b| b.1.cmp(&a.1));
+
+    println!("{} call sites changed since the snapshot", changes.len());
+    for (count, bytes, frames) in changes.iter().take(TRACK_REPORT_SITES) {
+        print_site(*count, *bytes, frames);
+    }
+    TRACKER.lock().snap = Some(snap);
+}
@@ -0,0 +1,69 @@
+//! Uncached mappings of device memory and firmware tables above the
+//! identity mapped low memory. Frames are mapped into a window of their own
+//! and stay mapped. A range whose frames already sit in consecutive slots
+//! reuses them, otherwise it gets new slots, so a frame shared by ranges
+//! may be mapped more than once.
+
+use crate::kernel_const::{FRAME_SIZE, FRAME_SIZE_BIT_WIDTH};
+use crate::memory::paging::g8_page_table::PAGE_TABLE;
+use crate::util::IrqLocked;
+use x86_64::structures::paging::{
+    mapper::MapToError, PageTableFlags, PhysFrame, Size2MiB, UnusedPhysFrame,
+};
+use x86_64::{PhysAddr, VirtAddr};
+
+const MMIO_START_ADDR: u64 = 0x7000000000;
+const MMIO_SLOTS: usize = 64;
+
+struct MmioWindow {
+    /// the physical frame mapped at each slot
+    frames: [Option<u64>; MMIO_SLOTS],
+}
+
+static WINDOW: IrqLocked<MmioWindow> = IrqLocked::new(MmioWindow {
+    frames: [None; MMIO_SLOTS],
+});
+
+fn slot_addr(i: usize) -> u64 {
+    MMIO_START_ADDR + ((i as u64) << FRAME_SIZE_BIT_WIDTH)
+}
+
+/// Maps the physical range `phys..phys + size` and returns the virtual
+/// address of `phys`. The frames of a range are mapped to consecutive slots.
+pub fn map(phys: u64, size: u64) -> Result<u64, MapToError<Size2MiB>> {
+    let first = phys & !(FRAME_SIZE - 1);
+    // an empty range still gets the frame of `phys`, its address has to be valid
+    let n = core::cmp::max(((phys + size - first + FRAME_SIZE - 1) >> FRAME_SIZE_BIT_WIDTH) as usize, 1);
+    let mut window = WINDOW.lock();
+
+    // already mapped in one piece
+    let found = (0..MMIO_SLOTS.saturating_sub(n - 1)).find(|i| {
+        (0..n).all(|j| window.frames[i + j] == Some(first + ((j as u64) << FRAME_SIZE_BIT_WIDTH)))
+    });
+    if let Some(i) = found {
+        return Ok(slot_addr(i) + phys - first);
+    }
+
+    let i = (0..MMIO_SLOTS.saturating_sub(n - 1))
+        .find(|i| (0..n).all(|j| window.frames[i + j].is_none()))
+        .ok_or(MapToError::FrameAllocationFailed)?;
+    let flags = PageTableFlags::PRESENT
+        | PageTableFlags::WRITABLE
+        | PageTableFlags::NO_CACHE
+        | PageTableFlags::WRITE_THROUGH;
+    for j in 0..n {
+        let frame_addr = first + ((j as u64) << FRAME_SIZE_BIT_WIDTH);
+        // device memory, not a frame of the frame allocator
+        let frame = unsafe {
+            UnusedPhysFrame::new(PhysFrame::<Size2MiB>::containing_address(PhysAddr::new(
+                frame_addr,
+            )))
+        };
+        PAGE_TABLE
+            .lock()
+            .map_to(VirtAddr::new(slot_addr(i + j)), frame, flags)?
+            .flush();
+        window.frames[i + j] = Some(frame_addr);
+    }
+    Ok(slot_addr(i) + phys - first)
+}
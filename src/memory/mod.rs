@@ -1,3 +1,7 @@
+pub mod arena;
 pub mod frame_controller;
 pub mod heap_allocator;
+pub mod mmio;
+pub mod oom;
 pub mod paging;
+pub mod stack_controller;
@@ -0,0 +1,76 @@
+//! Out of memory handling. When the heap can't satisfy an allocation the
+//! registered reclaim callbacks get a chance to give memory back (caches,
+//! queued log messages, ...) and the allocation is retried before the
+//! global allocator returns null.
+//!
+//! Reclaim callbacks may run in interrupt context, they must not block.
+
+use crate::memory::frame_controller::FRAME_ALLOC;
+use crate::memory::heap_allocator;
+use crate::println;
+use crate::util::IrqLocked;
+use alloc::alloc::Layout;
+use core::ptr;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+const MAX_RECLAIMERS: usize = 16;
+const OOM_RETRIES: usize = 3;
+
+/// Frees what it can and returns roughly how many bytes went back to the heap
+pub type Reclaim = fn() -> usize;
+
+static RECLAIMERS: IrqLocked<[Option<Reclaim>; MAX_RECLAIMERS]> =
+    IrqLocked::new([None; MAX_RECLAIMERS]);
+static IN_RECLAIM: AtomicBool = AtomicBool::new(false);
+
+/// Registers a reclaim callback, returns false when the table is full.
+pub fn register_reclaim(f: Reclaim) -> bool {
+    let mut reclaimers = RECLAIMERS.lock();
+    match reclaimers.iter_mut().find(|r| r.is_none()) {
+        Some(slot) => {
+            *slot = Some(f);
+            true
+        }
+        None => false,
+    }
+}
+
+/// Runs every reclaim callback, returns the bytes they claim to have freed.
+pub fn reclaim() -> usize {
+    // callbacks free memory, so they must run without the table locked
+    let reclaimers = *RECLAIMERS.lock();
+    reclaimers.iter().filter_map(|r| *r).map(|f| f()).sum()
+}
+
+/// Called by the global allocator once the heap is exhausted: reclaims and
+/// retries `alloc` until it succeeds or nothing more can be reclaimed.
+pub fn retry<F>(mut alloc: F) -> *mut u8
+where
+    F: FnMut() -> *mut u8,
+{
+    // an allocation failing inside a reclaim callback must not recurse
+    if IN_RECLAIM.swap(true, Ordering::Acquire) {
+        return ptr::null_mut();
+    }
+
+    let mut p = ptr::null_mut();
+    for _ in 0..OOM_RETRIES {
+        if reclaim() == 0 {
+            break;
+        }
+        p = alloc();
+        if !p.is_null() {
+            break;
+        }
+    }
+    IN_RECLAIM.store(false, Ordering::Release);
+    p
+}
+
+/// Prints the heap and frame allocator state after an allocation failed for good.
+pub fn report(layout: Layout) {
+    println!("OUT OF MEMORY: allocation of {:?} failed", layout);
+    heap_allocator::print_stats();
+    let frames = FRAME_ALLOC.lock().free_frames();
+    println!("frames: {} free ({}M)", frames, frames * 2);
+}
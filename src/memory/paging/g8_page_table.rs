@@ -1,8 +1,8 @@
+use crate::util::IrqLocked;
 use crate::kernel_const::{
     PAGE_TABLE_END, PAGE_TABLE_P2, PAGE_TABLE_P3, PAGE_TABLE_P4, PAGE_TABLE_START,
 };
 use lazy_static::lazy_static;
-use spin::Mutex;
 use x86_64::structures::paging::{
     mapper::{FlagUpdateError, MapToError, MapperFlush, PhysToVirt, TranslateError, UnmapError},
     page_table::PageTableFlags,
@@ -16,7 +16,7 @@ pub const NUMBER_OF_FRAMES: usize =
     ((PAGE_TABLE_END - PAGE_TABLE_START) / PAGE_FRAME_SIZE as u64 - 3) as usize;
 
 lazy_static! {
-    pub static ref PAGE_TABLE: Mutex<G8PagTable<'static>> = Mutex::new({
+    pub static ref PAGE_TABLE: IrqLocked<G8PagTable<'static>> = IrqLocked::new({
         let l4_table = active_l4_page_table();
         let page_allocator: PageTableAlloc = {
             let mut frames = [0; NUMBER_OF_FRAMES];
@@ -54,7 +54,7 @@ unsafe impl FrameAllocator<Size4KiB> for PageTableAlloc {
             j => unsafe {
                 self.next += 1;
                 Some(UnusedPhysFrame::new(PhysFrame::containing_address(
-                    PhysAddr::new(j as u64),
+                    PhysAddr::new(self.avail_frame[j]),
                 )))
             },
         }
@@ -0,0 +1,80 @@
+//! Kernel stacks other than the boot stack. Each stack is a run of frames
+//! in a window of its own with an unmapped frame below it, so running off
+//! the end of a stack page faults instead of silently overwriting whatever
+//! lies below. Stacks are never freed.
+
+use crate::kernel_const::{FRAME_SIZE, FRAME_SIZE_BIT_WIDTH};
+use crate::memory::frame_controller::FRAME_ALLOC;
+use crate::memory::paging::g8_page_table::PAGE_TABLE;
+use crate::util::IrqLocked;
+use x86_64::structures::paging::{mapper::MapToError, PageTableFlags, Size2MiB};
+use x86_64::VirtAddr;
+
+const STACK_AREA_START: u64 = 0x6000000000;
+const STACK_AREA_END: u64 = 0x7000000000;
+const STACK_MAX_STACKS: usize = 16;
+
+/// A mapped stack, `top` is the first byte above it.
+#[derive(Debug, Clone, Copy)]
+pub struct Stack {
+    pub name: &'static str,
+    pub bottom: u64,
+    pub top: u64,
+}
+
+struct StackController {
+    /// where the guard frame of the next stack goes
+    next: u64,
+    stacks: [Option<Stack>; STACK_MAX_STACKS],
+}
+
+static STACKS: IrqLocked<StackController> = IrqLocked::new(StackController {
+    next: STACK_AREA_START,
+    stacks: [None; STACK_MAX_STACKS],
+});
+
+/// Maps a stack of at least `size` bytes, rounded up to whole frames.
+pub fn alloc_stack(name: &'static str, size: u64) -> Result<Stack, MapToError<Size2MiB>> {
+    let frames = (size + FRAME_SIZE - 1) >> FRAME_SIZE_BIT_WIDTH;
+    let mut controller = STACKS.lock();
+    let slot = controller
+        .stacks
+        .iter()
+        .position(|s| s.is_none())
+        .ok_or(MapToError::FrameAllocationFailed)?;
+    let bottom = controller.next + FRAME_SIZE;
+    let top = bottom + (frames << FRAME_SIZE_BIT_WIDTH);
+    if top > STACK_AREA_END {
+        return Err(MapToError::FrameAllocationFailed);
+    }
+
+    // a failed stack leaves what it mapped behind, its space is not reused
+    controller.next = top;
+
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+    for addr in (bottom..top).step_by(FRAME_SIZE as usize) {
+        let frame = FRAME_ALLOC
+            .lock()
+            .allocate()
+            .ok_or(MapToError::FrameAllocationFailed)?;
+        PAGE_TABLE
+            .lock()
+            .map_to(VirtAddr::new(addr), frame, flags)?
+            .flush();
+    }
+
+    let stack = Stack { name, bottom, top };
+    controller.stacks[slot] = Some(stack);
+    Ok(stack)
+}
+
+/// the stack `addr` lies on
+pub fn stack_of(addr: u64) -> Option<Stack> {
+    STACKS
+        .lock()
+        .stacks
+        .iter()
+        .flatten()
+        .find(|s| addr >= s.bottom && addr < s.top)
+        .copied()
+}
@@ -1,5 +1,6 @@
-use super::{Task, TaskId};
+use super::{SpawnError, Task, TaskId};
 use alloc::{collections::BTreeMap, sync::Arc, task::Wake};
+use core::future::Future;
 use core::task::{Context, Poll, Waker};
 use crossbeam_queue::ArrayQueue;
 
@@ -53,7 +54,13 @@ impl Executor {
         self.task_queue.push(_id).expect("queue full");
     }
 
-    fn run_ready_tasks(&mut self) {
+    /// spawns `future` as a new task, unless the executor arena is full
+    pub fn try_spawn(&mut self, future: impl Future<Output = ()> + 'static) -> Result<(), SpawnError> {
+        self.spawn(Task::try_new(future)?);
+        Ok(())
+    }
+
+    pub(crate) fn run_ready_tasks(&mut self) {
         let Self {
             tasks,
             task_queue,
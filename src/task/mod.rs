@@ -1,3 +1,4 @@
+use crate::memory::arena::{Arena, EXECUTOR_ARENA};
 use alloc::boxed::Box;
 use core::{
     future::Future,
@@ -21,18 +22,42 @@ impl TaskId {
 
 pub struct Task {
     id: TaskId,
-    inner: Pin<Box<dyn Future<Output = ()>>>,
+    inner: Pin<Box<dyn Future<Output = ()>, &'static Arena>>,
 }
 
+/// The future of a task didn't fit into `EXECUTOR_ARENA`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SpawnError;
+
 impl Task {
-    pub fn new(future: impl Future<Output = ()> + 'static) -> Task {
-        Task {
+    /// Fails once the executor arena is over its quota, rather than taking
+    /// the kernel down the way an infallible allocation would.
+    pub fn try_new(future: impl Future<Output = ()> + 'static) -> Result<Task, SpawnError> {
+        let inner = Box::try_new_in(future, &EXECUTOR_ARENA).map_err(|_| SpawnError)?;
+        Ok(Task {
             id: TaskId::new(),
-            inner: Box::pin(future),
-        }
+            inner: Box::into_pin(inner),
+        })
     }
 
     pub fn poll(&mut self, context: &mut Context) -> Poll<()> {
         self.inner.as_mut().poll(context)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::executor::Executor;
+    use super::*;
+
+    #[test]
+    fn spawn_fails_over_the_executor_quota() {
+        let quota = EXECUTOR_ARENA.stats().quota;
+        EXECUTOR_ARENA.set_quota(EXECUTOR_ARENA.stats().in_use + 1024);
+        let mut executor = Executor::new();
+        let big = [0u8; 4096];
+        assert_eq!(executor.try_spawn(async move { let _big = big; }), Err(SpawnError));
+        assert_eq!(executor.try_spawn(async {}), Ok(()));
+        EXECUTOR_ARENA.set_quota(quota);
+    }
+}
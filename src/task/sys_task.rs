@@ -1,5 +1,5 @@
-//! To avoid the dead look when allocator memory in heap, 
-//! Dont' new any object in interrupt handler.
+//! The heap lock masks interrupts, so a handler may allocate,
+//! but it should still do as little as possible.
 //! Wrapper all interrupt handler to SysTask
 //! and then push it the system task queue, 
 //! the corresponding function will be called by executor
@@ -11,15 +11,20 @@ use crossbeam_queue::ArrayQueue;
 use spin::Mutex;
 use futures_util::{stream::Stream, stream::StreamExt, task::AtomicWaker};
 use core::{
-    fmt::{Arguments, Error, Write},
+    fmt::{Arguments, Write},
     pin::Pin,
     task::{Context, Poll},
 };
 use crate::util::{Locked, Flag};
 use lazy_static::lazy_static;
 use pc_keyboard::{layouts, DecodedKey, HandleControl, Keyboard, ScancodeSet1, KeyCode};
-use alloc::string::String;
+use crate::memory::arena::{self, ArenaString, DRIVER_ARENA};
 use crate::console::sys_log;
+use crate::memory::heap_allocator;
+use crate::idt;
+use crate::irq;
+use crate::extable;
+use crate::time;
 
 static SYS_TASK_QUEUE: OnceCell<ArrayQueue<SysTask>> = OnceCell::uninit();
 static SYS_TASK_WAKER: AtomicWaker = AtomicWaker::new();
@@ -44,8 +49,10 @@ impl SysTask{
     pub fn run(&self) {
         match self {
             Self::TIMMER(i) =>{
-                if i % 6 == 0 {
-                    let j = i / 6;
+                irq::report_unexpected();
+                // once a second at whatever rate the PIT was set to
+                if i % time::tick_hz() == 0 {
+                    let j = time::uptime().as_secs();
                 
                     match j % 4 {
                         0 => error!("time: {}", j),
@@ -138,24 +145,28 @@ pub fn add_sys_task(task: SysTask) {
 
 #[derive(Debug)]
 pub struct SysCmd {
-    buf: String,
+    buf: ArenaString,
 }
 
 impl SysCmd {
     fn new() -> Self {
         SysCmd{
-            buf: String::new(),
+            buf: ArenaString::new_in(&DRIVER_ARENA),
         }
     }
 
-    fn write(&mut self, args: Arguments) -> Result<(), Error> {
-        self.buf.write_fmt(args)
+    /// the buffer lives in the driver arena, so it fills up once the
+    /// arena's quota does; the rest of the line is dropped then
+    fn write(&mut self, args: Arguments) {
+        if self.buf.write_fmt(args).is_err() {
+            warn!("command too long, input dropped");
+        }
     }
 
     pub fn input_char(&mut self, c: char){
         match c {
             '\n' => self.run(),
-            _ => self.write(format_args!("{}", c)).unwrap(),
+            _ => self.write(format_args!("{}", c)),
         }
     }
 
@@ -163,13 +174,28 @@ impl SysCmd {
         
         match k {
             KeyCode::Enter => self.run(),
-            _ => self.write(format_args!("{:?}", k)).unwrap(),
+            _ => self.write(format_args!("{:?}", k)),
         }
     }
 
     fn run(&mut self ) {
-        let cmd = self.buf.as_ref();
-        sys_log::SYS_LOG_LEVEL.lock().conf(cmd);
+        let cmd = self.buf.as_str();
+        match cmd {
+            "heap" => heap_allocator::print_stats(),
+            "arenas" => arena::print_stats(),
+            "irq" => idt::print_stats(),
+            "date" => match time::rtc::now() {
+                Some(now) => println!("{}", now),
+                None => println!("no wall-clock time"),
+            },
+            _ if cmd.starts_with("peek ") => extable::print_peek(&cmd[5..]),
+            "leaks" => heap_allocator::track::report(),
+            "leaks on" => heap_allocator::track::start(),
+            "leaks off" => heap_allocator::track::stop(),
+            "leaks snap" => heap_allocator::track::snapshot(),
+            "leaks diff" => heap_allocator::track::diff(),
+            _ => sys_log::SYS_LOG_LEVEL.lock().conf(cmd),
+        }
         self.buf.clear();
     }
 }
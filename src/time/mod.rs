@@ -0,0 +1,165 @@
+//! The monotonic clock. The PIT interrupts `TICK_HZ` times a second and the
+//! timer handler counts the ticks, `Instant`s are measured in nanoseconds
+//! since `init`, at tick granularity. Tasks wait for them with `sleep`,
+//! `interval` and `timeout`. Wall-clock time comes from `rtc`, finer timing
+//! from `tsc`.
+
+pub mod pit;
+pub mod rtc;
+pub mod tsc;
+mod timer;
+
+pub use timer::{interval, sleep, sleep_until, timeout, Elapsed, Interval, Sleep, Timeout};
+
+use core::ops::{Add, AddAssign, Sub};
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::time::Duration;
+
+/// how often the timer interrupts, by default
+pub const TICK_HZ: u64 = 100;
+
+const NANOS_PER_SEC: u64 = 1_000_000_000;
+
+static TICKS: AtomicU64 = AtomicU64::new(0);
+/// PIT input clocks per tick
+static TICK_DIVISOR: AtomicU64 = AtomicU64::new(1 << 16);
+
+/// Programs the PIT to tick about `hz` times a second, before the timer
+/// interrupt is unmasked.
+pub fn init(hz: u64) {
+    let divisor = pit::divisor_for(hz);
+    TICK_DIVISOR.store(divisor, Ordering::Relaxed);
+    pit::set_divisor(divisor);
+}
+
+/// Counts a tick and wakes the timers it makes due, called by the timer
+/// interrupt. Returns the ticks before.
+pub fn tick() -> u64 {
+    let before = TICKS.fetch_add(1, Ordering::Relaxed);
+    timer::expire(before + 1);
+    before
+}
+
+/// ticks since `init`
+pub fn ticks() -> u64 {
+    TICKS.load(Ordering::Relaxed)
+}
+
+/// the actual tick frequency in mHz, the PIT can't hit every rate exactly
+pub fn tick_millihertz() -> u64 {
+    pit::PIT_FREQUENCY * 1000 / TICK_DIVISOR.load(Ordering::Relaxed)
+}
+
+/// the tick frequency `init` set, rounded to whole ticks a second
+pub fn tick_hz() -> u64 {
+    core::cmp::max((tick_millihertz() + 500) / 1000, 1)
+}
+
+pub fn ticks_to_ns(ticks: u64) -> u64 {
+    let divisor = TICK_DIVISOR.load(Ordering::Relaxed) as u128;
+    (ticks as u128 * divisor * NANOS_PER_SEC as u128 / pit::PIT_FREQUENCY as u128) as u64
+}
+
+/// the ticks in `ns`, rounded up
+pub fn ns_to_ticks(ns: u64) -> u64 {
+    let per_tick = TICK_DIVISOR.load(Ordering::Relaxed) as u128 * NANOS_PER_SEC as u128;
+    let ticks = (ns as u128 * pit::PIT_FREQUENCY as u128 + per_tick - 1) / per_tick;
+    ticks as u64
+}
+
+/// time since `init`
+pub fn uptime() -> Duration {
+    Duration::from_nanos(ticks_to_ns(ticks()))
+}
+
+/// A point on the monotonic clock.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Instant(u64);
+
+impl Instant {
+    pub fn now() -> Self {
+        Instant(ticks_to_ns(ticks()))
+    }
+
+    pub fn as_nanos(&self) -> u64 {
+        self.0
+    }
+
+    /// zero if `earlier` is later
+    pub fn duration_since(&self, earlier: Instant) -> Duration {
+        Duration::from_nanos(self.0.saturating_sub(earlier.0))
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        Instant::now().duration_since(*self)
+    }
+
+    pub fn checked_add(&self, duration: Duration) -> Option<Instant> {
+        let ns = duration.as_nanos();
+        if ns > u64::MAX as u128 {
+            return None;
+        }
+        self.0.checked_add(ns as u64).map(Instant)
+    }
+
+    pub fn checked_sub(&self, duration: Duration) -> Option<Instant> {
+        let ns = duration.as_nanos();
+        if ns > u64::MAX as u128 {
+            return None;
+        }
+        self.0.checked_sub(ns as u64).map(Instant)
+    }
+}
+
+impl Add<Duration> for Instant {
+    type Output = Instant;
+
+    fn add(self, duration: Duration) -> Instant {
+        self.checked_add(duration).expect("overflow adding duration to instant")
+    }
+}
+
+impl AddAssign<Duration> for Instant {
+    fn add_assign(&mut self, duration: Duration) {
+        *self = *self + duration;
+    }
+}
+
+impl Sub<Duration> for Instant {
+    type Output = Instant;
+
+    fn sub(self, duration: Duration) -> Instant {
+        self.checked_sub(duration).expect("overflow subtracting duration from instant")
+    }
+}
+
+impl Sub<Instant> for Instant {
+    type Output = Duration;
+
+    fn sub(self, earlier: Instant) -> Duration {
+        self.duration_since(earlier)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ticks_and_nanoseconds_convert_both_ways() {
+        let divisor = TICK_DIVISOR.load(Ordering::Relaxed);
+        let tick_ns = ticks_to_ns(1);
+        assert_eq!(tick_ns, divisor * NANOS_PER_SEC / pit::PIT_FREQUENCY);
+        assert_eq!(ns_to_ticks(0), 0);
+        assert_eq!(ns_to_ticks(1), 1);
+        assert_eq!(ns_to_ticks(ticks_to_ns(1000)), 1000);
+        assert_eq!(ns_to_ticks(ticks_to_ns(1000) + 1), 1001);
+    }
+
+    #[test]
+    fn tick_hz_follows_the_divisor() {
+        // nothing calls `init`, the PIT's slowest rate of 18.2 Hz stays
+        assert_eq!(TICK_DIVISOR.load(Ordering::Relaxed), 1 << 16);
+        assert_eq!(tick_hz(), 18);
+    }
+}
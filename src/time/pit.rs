@@ -0,0 +1,69 @@
+//! The 8253/8254 programmable interval timer. Channel 0 is the source of
+//! the timer interrupt on IRQ 0, channel 2 a one shot for calibrating the
+//! TSC.
+
+use x86_64::instructions::port::Port;
+
+/// the input clock of the PIT in Hz
+pub const PIT_FREQUENCY: u64 = 1_193_182;
+
+const PIT_CHANNEL_0: u16 = 0x40;
+const PIT_CHANNEL_2: u16 = 0x42;
+const PIT_COMMAND: u16 = 0x43;
+/// channel 0, low then high byte of the divisor, mode 3 (square wave)
+const PIT_CHANNEL_0_SQUARE_WAVE: u8 = 0b0011_0110;
+/// channel 2, low then high byte of the count, mode 0 (one shot)
+const PIT_CHANNEL_2_ONE_SHOT: u8 = 0b1011_0000;
+
+/// NMI status and control, bit 0 gates channel 2, bit 1 connects it to the
+/// speaker, bit 5 is its output
+const SYSTEM_CONTROL_B: u16 = 0x61;
+const CHANNEL_2_GATE: u8 = 1 << 0;
+const CHANNEL_2_SPEAKER: u8 = 1 << 1;
+const CHANNEL_2_OUT: u8 = 1 << 5;
+/// polls of channel 2 before giving up on it, a missing PIT never finishes
+const CHANNEL_2_MAX_POLLS: u64 = 1_000_000;
+
+/// The divisor closest to `hz` the 16 bit counter can take, 0 stands for
+/// 65536.
+pub fn divisor_for(hz: u64) -> u64 {
+    let divisor = (PIT_FREQUENCY + hz / 2) / hz.max(1);
+    divisor.max(1).min(1 << 16)
+}
+
+/// Lets channel 0 fire every `divisor` input clocks.
+pub fn set_divisor(divisor: u64) {
+    let mut command = Port::<u8>::new(PIT_COMMAND);
+    let mut data = Port::<u8>::new(PIT_CHANNEL_0);
+    unsafe {
+        command.write(PIT_CHANNEL_0_SQUARE_WAVE);
+        data.write(divisor as u8);
+        data.write((divisor >> 8) as u8);
+    }
+}
+
+/// Starts channel 2 counting `count` input clocks down, with the speaker
+/// off. `channel_2_done` tells when it got to 0.
+pub fn start_channel_2(count: u16) {
+    let mut control = Port::<u8>::new(SYSTEM_CONTROL_B);
+    let mut command = Port::<u8>::new(PIT_COMMAND);
+    let mut data = Port::<u8>::new(PIT_CHANNEL_2);
+    unsafe {
+        let c = control.read();
+        control.write((c & !CHANNEL_2_SPEAKER) & !CHANNEL_2_GATE);
+        command.write(PIT_CHANNEL_2_ONE_SHOT);
+        data.write(count as u8);
+        data.write((count >> 8) as u8);
+        // counting starts on the rising edge of the gate
+        control.write((c & !CHANNEL_2_SPEAKER) | CHANNEL_2_GATE);
+    }
+}
+
+pub fn channel_2_done() -> bool {
+    unsafe { Port::<u8>::new(SYSTEM_CONTROL_B).read() & CHANNEL_2_OUT != 0 }
+}
+
+/// Polls channel 2 until it got to 0, false if it never does.
+pub fn wait_channel_2() -> bool {
+    (0..CHANNEL_2_MAX_POLLS).any(|_| channel_2_done())
+}
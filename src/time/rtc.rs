@@ -0,0 +1,277 @@
+//! The CMOS real-time clock and wall-clock time. The RTC is read once at
+//! boot, its second-granular time pinned to the monotonic clock, and wall
+//! time runs on from there with the ticks. The RTC is assumed to keep UTC.
+//! An RTC which never finishes an update leaves the kernel without
+//! wall-clock time.
+
+use super::Instant;
+use crate::{no_interrupt, println};
+use core::fmt;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use x86_64::instructions::port::Port;
+
+const CMOS_ADDRESS: u16 = 0x70;
+const CMOS_DATA: u16 = 0x71;
+
+const RTC_SECONDS: u8 = 0x00;
+const RTC_MINUTES: u8 = 0x02;
+const RTC_HOURS: u8 = 0x04;
+const RTC_DAY: u8 = 0x07;
+const RTC_MONTH: u8 = 0x08;
+const RTC_YEAR: u8 = 0x09;
+/// where ACPI machines usually keep the century
+const RTC_CENTURY: u8 = 0x32;
+const RTC_STATUS_A: u8 = 0x0a;
+const RTC_STATUS_B: u8 = 0x0b;
+
+const STATUS_A_UPDATING: u8 = 1 << 7;
+const STATUS_B_24_HOUR: u8 = 1 << 1;
+const STATUS_B_BINARY: u8 = 1 << 2;
+const HOUR_PM: u8 = 1 << 7;
+
+/// status A polls before an update counts as stuck, an update takes under
+/// 2ms and a CMOS read about a microsecond
+const RTC_UPDATE_SPINS: u32 = 100_000;
+/// reads which may disagree with the one before before giving up
+const RTC_READ_TRIES: u32 = 8;
+
+const NANOS_PER_SEC: u64 = 1_000_000_000;
+const SECS_PER_DAY: u64 = 86400;
+
+/// nanoseconds from the Unix epoch to `Instant` 0, once `WALL_CLOCK` is set
+static BOOT_UNIX_NS: AtomicU64 = AtomicU64::new(0);
+/// whether `init` got a time from the RTC
+static WALL_CLOCK: AtomicBool = AtomicBool::new(false);
+
+/// A UTC calendar date and time.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DateTime {
+    pub year: u32,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+}
+
+impl fmt::Display for DateTime {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:04}-{:02}-{:02} {:02}:{:02}:{:02} UTC",
+            self.year, self.month, self.day, self.hour, self.minute, self.second
+        )
+    }
+}
+
+/// days since 1970-01-01 of a date in the proleptic Gregorian calendar
+fn days_from_civil(year: u32, month: u8, day: u8) -> u64 {
+    // years start in March, so the leap day is the last day of a year
+    let y = if month <= 2 { year - 1 } else { year } as u64;
+    let era = y / 400;
+    let yoe = y - era * 400;
+    let m = month as u64;
+    let doy = (153 * (if m > 2 { m - 3 } else { m + 9 }) + 2) / 5 + day as u64 - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146097 + doe - 719468
+}
+
+fn civil_from_days(days: u64) -> (u32, u8, u8) {
+    let z = days + 719468;
+    let era = z / 146097;
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u8;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u8;
+    let year = (yoe + era * 400) as u32 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+impl DateTime {
+    /// seconds since the Unix epoch, dates before it count as the epoch
+    pub fn unix_seconds(&self) -> u64 {
+        if self.year < 1970 {
+            return 0;
+        }
+        days_from_civil(self.year, self.month, self.day) * SECS_PER_DAY
+            + self.hour as u64 * 3600
+            + self.minute as u64 * 60
+            + self.second as u64
+    }
+
+    pub fn from_unix_seconds(secs: u64) -> DateTime {
+        let (year, month, day) = civil_from_days(secs / SECS_PER_DAY);
+        let secs = secs % SECS_PER_DAY;
+        DateTime {
+            year,
+            month,
+            day,
+            hour: (secs / 3600) as u8,
+            minute: (secs / 60 % 60) as u8,
+            second: (secs % 60) as u8,
+        }
+    }
+}
+
+fn read_cmos(reg: u8) -> u8 {
+    no_interrupt!(|| unsafe {
+        Port::<u8>::new(CMOS_ADDRESS).write(reg);
+        Port::<u8>::new(CMOS_DATA).read()
+    })
+}
+
+fn from_bcd(value: u8) -> u8 {
+    (value >> 4) * 10 + (value & 0x0f)
+}
+
+/// The raw registers, read with `read_cmos` twice outside of an update until
+/// both reads agree, so no field is caught halfway through a carry. None if
+/// an update doesn't finish or the reads keep changing.
+fn read_registers(read_cmos: impl Fn(u8) -> u8) -> Option<[u8; 7]> {
+    let read = || {
+        let mut spins = 0;
+        while read_cmos(RTC_STATUS_A) & STATUS_A_UPDATING != 0 {
+            spins += 1;
+            if spins == RTC_UPDATE_SPINS {
+                return None;
+            }
+        }
+        Some([
+            read_cmos(RTC_SECONDS),
+            read_cmos(RTC_MINUTES),
+            read_cmos(RTC_HOURS),
+            read_cmos(RTC_DAY),
+            read_cmos(RTC_MONTH),
+            read_cmos(RTC_YEAR),
+            read_cmos(RTC_CENTURY),
+        ])
+    };
+    let mut last = read()?;
+    for _ in 0..RTC_READ_TRIES {
+        let regs = read()?;
+        if regs == last {
+            return Some(regs);
+        }
+        last = regs;
+    }
+    None
+}
+
+/// Decodes the registers for the BCD or binary and 12 or 24 hour modes of
+/// `status_b`.
+fn decode(regs: [u8; 7], status_b: u8) -> DateTime {
+    let [second, minute, hour, day, month, year, century] = regs;
+    let pm = hour & HOUR_PM != 0;
+    let decode = |v: u8| {
+        if status_b & STATUS_B_BINARY != 0 {
+            v
+        } else {
+            from_bcd(v)
+        }
+    };
+    let mut hour = decode(hour & !HOUR_PM);
+    if status_b & STATUS_B_24_HOUR == 0 {
+        // 12 am is midnight, 12 pm noon
+        hour = hour % 12 + if pm { 12 } else { 0 };
+    }
+    // no century register reads as 0 or garbage
+    let century = match decode(century) {
+        c @ 19..=99 => c as u32,
+        _ => 20,
+    };
+    DateTime {
+        year: century * 100 + decode(year) as u32,
+        month: decode(month),
+        day: decode(day),
+        hour,
+        minute: decode(minute),
+        second: decode(second),
+    }
+}
+
+/// the date and time the RTC shows, None if it can't be read
+pub fn read() -> Option<DateTime> {
+    let regs = read_registers(read_cmos)?;
+    Some(decode(regs, read_cmos(RTC_STATUS_B)))
+}
+
+/// Pins wall-clock time to the monotonic clock, after `time::init`.
+pub fn init() {
+    match read() {
+        Some(rtc) => {
+            let unix_ns = rtc.unix_seconds() * NANOS_PER_SEC;
+            BOOT_UNIX_NS.store(unix_ns.saturating_sub(Instant::now().as_nanos()), Ordering::Relaxed);
+            WALL_CLOCK.store(true, Ordering::Release);
+        }
+        None => println!("rtc: the RTC is stuck in an update, no wall-clock time"),
+    }
+}
+
+/// nanoseconds since the Unix epoch, None without wall-clock time
+pub fn unix_nanos() -> Option<u64> {
+    if !WALL_CLOCK.load(Ordering::Acquire) {
+        return None;
+    }
+    Some(BOOT_UNIX_NS.load(Ordering::Relaxed) + Instant::now().as_nanos())
+}
+
+/// the current UTC date and time, None without wall-clock time
+pub fn now() -> Option<DateTime> {
+    unix_nanos().map(|ns| DateTime::from_unix_seconds(ns / NANOS_PER_SEC))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dates_convert_to_unix_time_and_back() {
+        let leap = DateTime {
+            year: 2024,
+            month: 2,
+            day: 29,
+            hour: 23,
+            minute: 59,
+            second: 58,
+        };
+        assert_eq!(leap.unix_seconds(), 1_709_251_198);
+        assert_eq!(DateTime::from_unix_seconds(1_709_251_198), leap);
+        assert_eq!(DateTime::from_unix_seconds(0).year, 1970);
+        for days in (0..200_000).step_by(7) {
+            let (y, m, d) = civil_from_days(days);
+            assert_eq!(days_from_civil(y, m, d), days);
+        }
+    }
+
+    #[test]
+    fn registers_decode_in_every_mode() {
+        // 2026-10-18 12:05:09 pm, BCD, 12 hour
+        let bcd = decode([0x09, 0x05, 0x12 | HOUR_PM, 0x18, 0x10, 0x26, 0x20], 0);
+        assert_eq!((bcd.year, bcd.month, bcd.day, bcd.hour), (2026, 10, 18, 12));
+        // 12:30 am is half past midnight
+        assert_eq!(decode([0, 0x30, 0x12, 1, 1, 0, 0], 0).hour, 0);
+        let binary = decode([9, 5, 21, 18, 10, 26, 0], STATUS_B_BINARY | STATUS_B_24_HOUR);
+        assert_eq!(binary.to_string(), "2026-10-18 21:05:09 UTC");
+    }
+
+    #[test]
+    fn stuck_updates_give_up() {
+        let regs = |reg: u8| if reg == RTC_STATUS_A { 0 } else { reg + 1 };
+        assert_eq!(read_registers(regs), Some([1, 3, 5, 8, 9, 10, 0x33]));
+        let updating = |reg: u8| if reg == RTC_STATUS_A { STATUS_A_UPDATING } else { 0 };
+        assert_eq!(read_registers(updating), None);
+        // the seconds change on every read
+        let n = core::cell::Cell::new(0u8);
+        let ticking = |reg: u8| match reg {
+            RTC_STATUS_A => 0,
+            RTC_SECONDS => {
+                n.set(n.get().wrapping_add(1));
+                n.get()
+            }
+            _ => 0,
+        };
+        assert_eq!(read_registers(ticking), None);
+    }
+}
@@ -0,0 +1,295 @@
+//! Timers for executor tasks. A pending `Sleep` puts its deadline into a
+//! queue which the timer interrupt checks on every tick, waking the tasks
+//! whose deadlines passed. Deadlines are rounded up to whole ticks.
+//!
+//! ```ignore
+//! time::sleep(Duration::from_millis(500)).await;
+//! let mut every_second = time::interval(Duration::from_secs(1));
+//! while let Some(_) = every_second.next().await { ... }
+//! let line = time::timeout(read_line(), Duration::from_secs(5)).await?;
+//! ```
+
+use super::{ns_to_ticks, ticks, Instant};
+use crate::util::IrqLocked;
+use alloc::collections::BinaryHeap;
+use alloc::sync::Arc;
+use core::cmp::Ordering as CmpOrdering;
+use core::future::Future;
+use core::mem;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use core::task::{Context, Poll};
+use core::time::Duration;
+use futures_util::stream::Stream;
+use futures_util::task::AtomicWaker;
+use lazy_static::lazy_static;
+
+struct TimerState {
+    fired: AtomicBool,
+    waker: AtomicWaker,
+}
+
+struct TimerEntry {
+    /// in ticks
+    deadline: u64,
+    state: Arc<TimerState>,
+}
+
+// the earliest deadline is the greatest, `BinaryHeap` is a max heap
+impl Ord for TimerEntry {
+    fn cmp(&self, other: &Self) -> CmpOrdering {
+        other.deadline.cmp(&self.deadline)
+    }
+}
+
+impl PartialOrd for TimerEntry {
+    fn partial_cmp(&self, other: &Self) -> Option<CmpOrdering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for TimerEntry {
+    fn eq(&self, other: &Self) -> bool {
+        self.deadline == other.deadline
+    }
+}
+
+impl Eq for TimerEntry {}
+
+lazy_static! {
+    static ref TIMERS: IrqLocked<BinaryHeap<TimerEntry>> = IrqLocked::new(BinaryHeap::new());
+}
+
+/// the earliest deadline in `TIMERS`, so most ticks don't take the lock
+static NEXT_DEADLINE: AtomicU64 = AtomicU64::new(u64::MAX);
+
+/// Wakes the timers due at tick `now`, called by the timer interrupt.
+pub(super) fn expire(now: u64) {
+    if now < NEXT_DEADLINE.load(Ordering::Relaxed) {
+        return;
+    }
+    let mut timers = TIMERS.lock();
+    while let Some(entry) = timers.peek() {
+        if entry.deadline > now {
+            break;
+        }
+        let entry = timers.pop().unwrap();
+        entry.state.fired.store(true, Ordering::Release);
+        entry.state.waker.wake();
+    }
+    let next = timers.peek().map_or(u64::MAX, |e| e.deadline);
+    NEXT_DEADLINE.store(next, Ordering::Relaxed);
+}
+
+fn add_timer(deadline: u64, state: Arc<TimerState>) {
+    let mut timers = TIMERS.lock();
+    timers.push(TimerEntry { deadline, state });
+    NEXT_DEADLINE.fetch_min(deadline, Ordering::Relaxed);
+}
+
+/// Takes the timer of `state` out of the queue, if it is still in there.
+/// Rebuilds the heap, a `BinaryHeap` can't remove from the middle.
+fn cancel_timer(state: &Arc<TimerState>) {
+    let mut timers = TIMERS.lock();
+    let mut entries = mem::take(&mut *timers).into_vec();
+    entries.retain(|e| !Arc::ptr_eq(&e.state, state));
+    *timers = BinaryHeap::from(entries);
+    let next = timers.peek().map_or(u64::MAX, |e| e.deadline);
+    NEXT_DEADLINE.store(next, Ordering::Relaxed);
+}
+
+/// Completes once its deadline passed. Dropping a pending `Sleep` takes its
+/// timer out of the queue.
+pub struct Sleep {
+    /// in ticks
+    deadline: u64,
+    state: Option<Arc<TimerState>>,
+}
+
+pub fn sleep(duration: Duration) -> Sleep {
+    sleep_until(Instant::now() + duration)
+}
+
+pub fn sleep_until(instant: Instant) -> Sleep {
+    Sleep {
+        deadline: ns_to_ticks(instant.as_nanos()),
+        state: None,
+    }
+}
+
+impl Sleep {
+    pub fn deadline(&self) -> Instant {
+        Instant(super::ticks_to_ns(self.deadline))
+    }
+}
+
+impl Future for Sleep {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        if ticks() >= self.deadline {
+            return Poll::Ready(());
+        }
+        match &self.state {
+            Some(state) => {
+                state.waker.register(cx.waker());
+                if state.fired.load(Ordering::Acquire) {
+                    return Poll::Ready(());
+                }
+            }
+            None => {
+                let state = Arc::new(TimerState {
+                    fired: AtomicBool::new(false),
+                    waker: AtomicWaker::new(),
+                });
+                // registered before the interrupt can see it
+                state.waker.register(cx.waker());
+                add_timer(self.deadline, state.clone());
+                self.state = Some(state);
+            }
+        }
+        // the tick may have come in meanwhile
+        if ticks() >= self.deadline {
+            Poll::Ready(())
+        } else {
+            Poll::Pending
+        }
+    }
+}
+
+impl Drop for Sleep {
+    fn drop(&mut self) {
+        if let Some(state) = &self.state {
+            if !state.fired.load(Ordering::Acquire) {
+                cancel_timer(state);
+            }
+        }
+    }
+}
+
+/// A stream which yields every `period`, see `interval`.
+pub struct Interval {
+    next: Instant,
+    period: Duration,
+    sleep: Sleep,
+}
+
+/// Yields the instant it was due every `period`, starting one period from
+/// now. Ticks missed while the task was busy are skipped, not made up.
+pub fn interval(period: Duration) -> Interval {
+    assert!(period.as_nanos() > 0, "interval period must not be zero");
+    let next = Instant::now() + period;
+    Interval {
+        next,
+        period,
+        sleep: sleep_until(next),
+    }
+}
+
+impl Stream for Interval {
+    type Item = Instant;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Instant>> {
+        if Pin::new(&mut self.sleep).poll(cx).is_pending() {
+            return Poll::Pending;
+        }
+        let due = self.next;
+        let now = Instant::now().as_nanos();
+        let period = self.period.as_nanos() as u64;
+        let mut next = due.as_nanos() + period;
+        if next <= now {
+            next += (now - next) / period * period + period;
+        }
+        self.next = Instant(next);
+        self.sleep = sleep_until(self.next);
+        Poll::Ready(Some(due))
+    }
+}
+
+/// The deadline of a `timeout` passed first.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Elapsed;
+
+pub struct Timeout<F> {
+    future: F,
+    sleep: Sleep,
+}
+
+/// Runs `future` for at most `duration`.
+pub fn timeout<F: Future>(future: F, duration: Duration) -> Timeout<F> {
+    Timeout {
+        future,
+        sleep: sleep(duration),
+    }
+}
+
+impl<F: Future> Future for Timeout<F> {
+    type Output = Result<F::Output, Elapsed>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        // `future` is never moved out of the pinned `Timeout`
+        let this = unsafe { self.get_unchecked_mut() };
+        let future = unsafe { Pin::new_unchecked(&mut this.future) };
+        if let Poll::Ready(output) = future.poll(cx) {
+            return Poll::Ready(Ok(output));
+        }
+        match Pin::new(&mut this.sleep).poll(cx) {
+            Poll::Ready(()) => Poll::Ready(Err(Elapsed)),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{tick, ticks_to_ns};
+    use super::*;
+    use alloc::task::Wake;
+    use core::task::Waker;
+
+    struct CountWakes(AtomicU64);
+
+    impl Wake for CountWakes {
+        fn wake(self: Arc<Self>) {
+            self.0.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    #[test]
+    fn ticks_wake_sleep_and_timeout() {
+        let wakes = Arc::new(CountWakes(AtomicU64::new(0)));
+        let waker = Waker::from(wakes.clone());
+        let mut cx = Context::from_waker(&waker);
+        let tick_ns = ticks_to_ns(1);
+
+        let mut sleep = sleep(Duration::from_nanos(tick_ns * 3));
+        let mut timeout = timeout(core::future::pending::<()>(), Duration::from_nanos(tick_ns));
+        assert!(Pin::new(&mut sleep).poll(&mut cx).is_pending());
+        assert!(Pin::new(&mut timeout).poll(&mut cx).is_pending());
+        tick();
+        assert_eq!(Pin::new(&mut timeout).poll(&mut cx), Poll::Ready(Err(Elapsed)));
+        tick();
+        assert!(Pin::new(&mut sleep).poll(&mut cx).is_pending());
+        tick();
+        assert_eq!(wakes.0.load(Ordering::Relaxed), 2);
+        assert!(Pin::new(&mut sleep).poll(&mut cx).is_ready());
+    }
+
+    #[test]
+    fn dropped_timeouts_leave_the_queue() {
+        let waker = Waker::from(Arc::new(CountWakes(AtomicU64::new(0))));
+        let mut cx = Context::from_waker(&waker);
+        let queued = || TIMERS.lock().len();
+
+        let before = queued();
+        for _ in 0..100 {
+            let mut t = timeout(core::future::pending::<()>(), Duration::from_secs(3600));
+            assert!(Pin::new(&mut t).poll(&mut cx).is_pending());
+            let state = Arc::downgrade(t.sleep.state.as_ref().unwrap());
+            drop(t);
+            assert!(state.upgrade().is_none());
+        }
+        // other tests may have timers of their own queued meanwhile
+        assert!(queued() < before + 100);
+    }
+}
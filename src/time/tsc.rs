@@ -0,0 +1,143 @@
+//! The time stamp counter, for timing at cycle resolution. `init` measures
+//! its frequency against PIT channel 2 at boot. `now_ns` runs on the TSC only
+//! if the CPU says it is invariant, ticking at a constant rate through power
+//! states, and the calibration runs agree. Otherwise it falls back to the
+//! ticks of the monotonic clock.
+
+use super::{pit, ticks, ticks_to_ns, Instant};
+use crate::{no_interrupt, println};
+use core::arch::x86_64::{__cpuid, _rdtsc};
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+const CPUID_EXTENDED_MAX: u32 = 0x8000_0000;
+const CPUID_POWER_MANAGEMENT: u32 = 0x8000_0007;
+const CPUID_EDX_INVARIANT_TSC: u32 = 1 << 8;
+
+/// each calibration run counts down this long, 10ms
+const CALIBRATE_COUNT: u16 = (pit::PIT_FREQUENCY / 100) as u16;
+const CALIBRATE_RUNS: usize = 3;
+/// runs further apart than 1/SPREAD of the fastest make the TSC unstable
+const CALIBRATE_SPREAD: u64 = 100;
+
+/// the TSC frequency, 0 until calibrated
+static KHZ: AtomicU64 = AtomicU64::new(0);
+static STABLE: AtomicBool = AtomicBool::new(false);
+/// the TSC and the monotonic clock at calibration
+static BASE_CYCLES: AtomicU64 = AtomicU64::new(0);
+static BASE_NS: AtomicU64 = AtomicU64::new(0);
+
+/// the time stamp counter
+pub fn cycles() -> u64 {
+    unsafe { _rdtsc() }
+}
+
+pub fn invariant() -> bool {
+    unsafe {
+        __cpuid(CPUID_EXTENDED_MAX).eax >= CPUID_POWER_MANAGEMENT
+            && __cpuid(CPUID_POWER_MANAGEMENT).edx & CPUID_EDX_INVARIANT_TSC != 0
+    }
+}
+
+/// TSC cycles while channel 2 counts `CALIBRATE_COUNT` down, `None` if it
+/// never gets there
+fn calibrate_once() -> Option<u64> {
+    no_interrupt!(|| {
+        pit::start_channel_2(CALIBRATE_COUNT);
+        let start = cycles();
+        if pit::wait_channel_2() {
+            Some(cycles() - start)
+        } else {
+            None
+        }
+    })
+}
+
+fn khz_from(cycles: u64, pit_count: u64) -> u64 {
+    (cycles as u128 * pit::PIT_FREQUENCY as u128 / pit_count as u128 / 1000) as u64
+}
+
+/// Whether the runs are close enough to trust, SMIs and emulators make some
+/// of them run long.
+fn consistent(runs: &[u64]) -> bool {
+    let min = runs.iter().copied().min().unwrap_or(0);
+    let max = runs.iter().copied().max().unwrap_or(0);
+    min != 0 && max - min <= min / CALIBRATE_SPREAD
+}
+
+fn cycles_to_ns_at(cycles: u64, khz: u64) -> u64 {
+    (cycles as u128 * 1_000_000 / khz as u128) as u64
+}
+
+/// Calibrates the TSC, after `time::init`.
+pub fn init() {
+    let mut runs = [0; CALIBRATE_RUNS];
+    for run in runs.iter_mut() {
+        match calibrate_once() {
+            Some(cycles) => *run = cycles,
+            None => {
+                println!("tsc: PIT channel 2 doesn't count, timing by ticks");
+                return;
+            }
+        }
+    }
+    // the fastest run had the fewest interruptions
+    let khz = khz_from(*runs.iter().min().unwrap(), CALIBRATE_COUNT as u64);
+    let stable = invariant() && consistent(&runs);
+    BASE_NS.store(Instant::now().as_nanos(), Ordering::Relaxed);
+    BASE_CYCLES.store(cycles(), Ordering::Relaxed);
+    KHZ.store(khz, Ordering::Relaxed);
+    STABLE.store(stable, Ordering::Release);
+    println!(
+        "tsc: {}.{:03} MHz, {}",
+        khz / 1000,
+        khz % 1000,
+        if stable { "invariant" } else { "unstable, timing by ticks" }
+    );
+}
+
+/// the calibrated TSC frequency in kHz
+pub fn khz() -> Option<u64> {
+    match KHZ.load(Ordering::Relaxed) {
+        0 => None,
+        khz => Some(khz),
+    }
+}
+
+/// whether `now_ns` runs on the TSC
+pub fn stable() -> bool {
+    STABLE.load(Ordering::Acquire)
+}
+
+/// `cycles` in nanoseconds, once calibrated. Only an estimate if the TSC
+/// isn't `stable`.
+pub fn cycles_to_ns(cycles: u64) -> Option<u64> {
+    khz().map(|khz| cycles_to_ns_at(cycles, khz))
+}
+
+/// Nanoseconds on the monotonic clock, at TSC resolution if it is `stable`
+/// and at tick resolution if not.
+pub fn now_ns() -> u64 {
+    if !stable() {
+        return ticks_to_ns(ticks());
+    }
+    let elapsed = cycles().wrapping_sub(BASE_CYCLES.load(Ordering::Relaxed));
+    BASE_NS.load(Ordering::Relaxed) + cycles_to_ns_at(elapsed, KHZ.load(Ordering::Relaxed))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn calibration_runs_convert_to_frequency() {
+        // 3GHz for 10ms
+        let khz = khz_from(30_000_000, CALIBRATE_COUNT as u64);
+        assert!((2_999_000..=3_001_000).contains(&khz));
+        assert_eq!(cycles_to_ns_at(3_000_000, 3_000_000), 1_000_000);
+        // 1GHz, a cycle a nanosecond, without overflowing
+        assert_eq!(cycles_to_ns_at(u64::MAX, 1_000_000), u64::MAX);
+        assert!(consistent(&[30_000_000, 30_100_000, 30_200_000]));
+        assert!(!consistent(&[30_000_000, 30_100_000, 31_000_000]));
+        assert!(!consistent(&[0, 0, 0]));
+    }
+}
@@ -0,0 +1,167 @@
+[BITS 64]
+
+; Exception entry stubs. Every stub pushes a zero error code if the CPU
+; didn't push one, then the vector, and jumps to trap_common which saves
+; the general purpose registers into a TrapFrame (see trap.rs) and calls
+; trap_dispatch with a pointer to it.
+
+global trap_stubs
+global read_cr0
+global read_cr2
+global read_cr3
+global read_cr4
+global probe_read_u8
+global probe_read_u64
+global probe_write_u8
+extern trap_dispatch
+
+section .text
+
+%macro TRAP 1
+trap_stub_%1:
+    push qword 0
+    push qword %1
+    jmp trap_common
+%endmacro
+
+%macro TRAP_ERR 1
+trap_stub_%1:
+    push qword %1
+    jmp trap_common
+%endmacro
+
+TRAP 0
+TRAP 1
+TRAP 2
+TRAP 3
+TRAP 4
+TRAP 5
+TRAP 6
+TRAP 7
+TRAP_ERR 8
+TRAP 9
+TRAP_ERR 10
+TRAP_ERR 11
+TRAP_ERR 12
+TRAP_ERR 13
+TRAP_ERR 14
+TRAP 15
+TRAP 16
+TRAP_ERR 17
+TRAP 18
+TRAP 19
+TRAP 20
+TRAP_ERR 21
+TRAP 22
+TRAP 23
+TRAP 24
+TRAP 25
+TRAP 26
+TRAP 27
+TRAP 28
+TRAP_ERR 29
+TRAP_ERR 30
+TRAP 31
+
+trap_common:
+    push rax
+    push rbx
+    push rcx
+    push rdx
+    push rsi
+    push rdi
+    push rbp
+    push r8
+    push r9
+    push r10
+    push r11
+    push r12
+    push r13
+    push r14
+    push r15
+    ; the CPU aligned rsp to 16 bytes before pushing 5 qwords, with the
+    ; error code, vector and 15 registers rsp is aligned again
+    mov rdi, rsp
+    cld
+    call trap_dispatch
+    pop r15
+    pop r14
+    pop r13
+    pop r12
+    pop r11
+    pop r10
+    pop r9
+    pop r8
+    pop rbp
+    pop rdi
+    pop rsi
+    pop rdx
+    pop rcx
+    pop rbx
+    pop rax
+    ; drop the vector and the error code
+    add rsp, 16
+    iretq
+
+read_cr0:
+    mov rax, cr0
+    ret
+
+read_cr2:
+    mov rax, cr2
+    ret
+
+read_cr3:
+    mov rax, cr3
+    ret
+
+read_cr4:
+    mov rax, cr4
+    ret
+
+; Probes: memory accesses which may fault. Each faulting instruction has an
+; .extable entry, a page fault or general protection fault on it resumes at
+; probe_fixup instead (see extable.rs). They return 0, or 1 on a fault.
+
+; u64 probe_read_u8(u64 addr, u8 *out)
+probe_read_u8:
+.access:
+    mov al, [rdi]
+    mov [rsi], al
+    xor eax, eax
+    ret
+
+; u64 probe_read_u64(u64 addr, u64 *out)
+probe_read_u64:
+.access:
+    mov rax, [rdi]
+    mov [rsi], rax
+    xor eax, eax
+    ret
+
+; u64 probe_write_u8(u64 addr, u8 value)
+probe_write_u8:
+.access:
+    mov [rdi], sil
+    xor eax, eax
+    ret
+
+probe_fixup:
+    mov eax, 1
+    ret
+
+; faulting instruction, where to go on
+section .extable
+align 8
+    dq probe_read_u8.access, probe_fixup
+    dq probe_read_u64.access, probe_fixup
+    dq probe_write_u8.access, probe_fixup
+
+section .rodata
+align 8
+trap_stubs:
+%assign i 0
+%rep 32
+    dq trap_stub_%+i
+%assign i i+1
+%endrep
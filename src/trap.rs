@@ -0,0 +1,312 @@
+//! CPU exceptions. Every architecturally defined exception vector enters
+//! through a stub in `trap.asm` which saves the general purpose registers
+//! into a `TrapFrame` and calls `trap_dispatch`. Faults of the probes in
+//! `extable` resume at their fixup. Breakpoints, debug traps and NMIs are
+//! reported and execution continues, everything else prints a crash report
+//! and halts.
+//!
+//! Control protection (21), hypervisor injection (28) and VMM communication
+//! (29) are left out, the kernel enables neither CET nor runs under SEV.
+
+use crate::backtrace;
+use crate::extable;
+use crate::gdt;
+use crate::hlt_loop;
+use crate::idt;
+use crate::time::tsc;
+use crate::{print, println};
+use core::mem;
+use x86_64::registers::model_specific::Msr;
+use x86_64::structures::idt::InterruptDescriptorTable;
+
+const IA32_EFER: u32 = 0xc000_0080;
+const CODE_BYTES: u64 = 15; // longest x86 instruction
+
+const PTE_PRESENT: u64 = 1 << 0;
+const PTE_HUGE: u64 = 1 << 7;
+const PTE_ADDR_MASK: u64 = 0x000f_ffff_ffff_f000;
+
+extern "C" {
+    static trap_stubs: [u64; 32];
+    fn read_cr0() -> u64;
+    fn read_cr2() -> u64;
+    fn read_cr3() -> u64;
+    fn read_cr4() -> u64;
+}
+
+/// The registers as `trap_common` leaves them on the stack, lowest address
+/// first.
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+pub struct TrapFrame {
+    pub r15: u64,
+    pub r14: u64,
+    pub r13: u64,
+    pub r12: u64,
+    pub r11: u64,
+    pub r10: u64,
+    pub r9: u64,
+    pub r8: u64,
+    pub rbp: u64,
+    pub rdi: u64,
+    pub rsi: u64,
+    pub rdx: u64,
+    pub rcx: u64,
+    pub rbx: u64,
+    pub rax: u64,
+    pub vector: u64,
+    /// 0 for vectors without an error code
+    pub error_code: u64,
+    pub rip: u64,
+    pub cs: u64,
+    pub rflags: u64,
+    pub rsp: u64,
+    pub ss: u64,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct ControlRegs {
+    cr0: u64,
+    cr2: u64,
+    cr3: u64,
+    cr4: u64,
+    efer: u64,
+}
+
+impl ControlRegs {
+    fn read() -> Self {
+        unsafe {
+            ControlRegs {
+                cr0: read_cr0(),
+                cr2: read_cr2(),
+                cr3: read_cr3(),
+                cr4: read_cr4(),
+                efer: Msr::new(IA32_EFER).read(),
+            }
+        }
+    }
+}
+
+/// mnemonic and name of an exception vector
+pub fn vector_name(vector: u64) -> (&'static str, &'static str) {
+    match vector {
+        0 => ("#DE", "DIVIDE ERROR"),
+        1 => ("#DB", "DEBUG"),
+        2 => ("NMI", "NON MASKABLE INTERRUPT"),
+        3 => ("#BP", "BREAKPOINT"),
+        4 => ("#OF", "OVERFLOW"),
+        5 => ("#BR", "BOUND RANGE EXCEEDED"),
+        6 => ("#UD", "INVALID OPCODE"),
+        7 => ("#NM", "DEVICE NOT AVAILABLE"),
+        8 => ("#DF", "DOUBLE FAULT"),
+        9 => ("---", "COPROCESSOR SEGMENT OVERRUN"),
+        10 => ("#TS", "INVALID TSS"),
+        11 => ("#NP", "SEGMENT NOT PRESENT"),
+        12 => ("#SS", "STACK SEGMENT FAULT"),
+        13 => ("#GP", "GENERAL PROTECTION"),
+        14 => ("#PF", "PAGE FAULT"),
+        16 => ("#MF", "X87 FLOATING POINT"),
+        17 => ("#AC", "ALIGNMENT CHECK"),
+        18 => ("#MC", "MACHINE CHECK"),
+        19 => ("#XM", "SIMD FLOATING POINT"),
+        20 => ("#VE", "VIRTUALIZATION"),
+        21 => ("#CP", "CONTROL PROTECTION"),
+        28 => ("#HV", "HYPERVISOR INJECTION"),
+        29 => ("#VC", "VMM COMMUNICATION"),
+        30 => ("#SX", "SECURITY"),
+        _ => ("---", "RESERVED"),
+    }
+}
+
+fn has_error_code(vector: u64) -> bool {
+    match vector {
+        8 | 10 | 11 | 12 | 13 | 14 | 17 | 21 | 29 | 30 => true,
+        _ => false,
+    }
+}
+
+/// Installs the stubs for every exception the CPU may raise.
+pub fn install(idt: &mut InterruptDescriptorTable) {
+    unsafe {
+        idt.divide_error.set_handler_fn(stub(0));
+        idt.debug.set_handler_fn(stub(1));
+        idt.non_maskable_interrupt
+            .set_handler_fn(stub(2))
+            .set_stack_index(gdt::NMI_IST_INDEX);
+        idt.breakpoint.set_handler_fn(stub(3));
+        idt.overflow.set_handler_fn(stub(4));
+        idt.bound_range_exceeded.set_handler_fn(stub(5));
+        idt.invalid_opcode.set_handler_fn(stub(6));
+        idt.device_not_available.set_handler_fn(stub(7));
+        idt.double_fault
+            .set_handler_fn(stub(8))
+            .set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX);
+        idt.invalid_tss.set_handler_fn(stub(10));
+        idt.segment_not_present.set_handler_fn(stub(11));
+        idt.stack_segment_fault.set_handler_fn(stub(12));
+        idt.general_protection_fault.set_handler_fn(stub(13));
+        idt.page_fault
+            .set_handler_fn(stub(14))
+            .set_stack_index(gdt::PAGE_FAULT_IST_INDEX);
+        idt.x87_floating_point.set_handler_fn(stub(16));
+        idt.alignment_check.set_handler_fn(stub(17));
+        idt.machine_check
+            .set_handler_fn(stub(18))
+            .set_stack_index(gdt::MACHINE_CHECK_IST_INDEX);
+        idt.simd_floating_point.set_handler_fn(stub(19));
+        idt.virtualization.set_handler_fn(stub(20));
+        idt.security_exception.set_handler_fn(stub(30));
+    }
+}
+
+/// The stub of `vector` as whatever handler type its IDT entry takes. The
+/// stubs follow no Rust ABI, the entry only needs their address.
+unsafe fn stub<F>(vector: usize) -> F {
+    mem::transmute_copy(&trap_stubs[vector])
+}
+
+#[no_mangle]
+extern "C" fn trap_dispatch(frame: &mut TrapFrame) {
+    // before anything else can fault and overwrite CR2
+    let regs = ControlRegs::read();
+    let start = tsc::cycles();
+    if frame.vector == 13 || frame.vector == 14 {
+        if let Some(fixup) = extable::fixup(frame.rip) {
+            frame.rip = fixup;
+            idt::record(frame.vector as u8, tsc::cycles() - start, false);
+            return;
+        }
+    }
+    report(frame, &regs);
+    idt::record(frame.vector as u8, tsc::cycles() - start, false);
+    match frame.vector {
+        1 | 2 | 3 => {}
+        _ => hlt_loop(),
+    }
+}
+
+fn print_flags(bits: u64, names: &[(u64, &str, &str)]) {
+    for (bit, set, clear) in names {
+        let name = if bits & bit != 0 { set } else { clear };
+        if !name.is_empty() {
+            print!(" {}", name);
+        }
+    }
+}
+
+/// what the error code of `vector` says
+fn print_error_code(vector: u64, code: u64) {
+    print!("error code 0x{:x}:", code);
+    match vector {
+        14 => print_flags(
+            code,
+            &[
+                (1 << 0, "protection", "not-present"),
+                (1 << 1, "write", "read"),
+                (1 << 2, "user", "kernel"),
+                (1 << 3, "reserved-bit", ""),
+                (1 << 4, "fetch", ""),
+                (1 << 5, "protection-key", ""),
+                (1 << 15, "sgx", ""),
+            ],
+        ),
+        10 | 11 | 12 | 13 | 17 if code == 0 => print!(" none"),
+        10 | 11 | 12 | 13 => {
+            let table = match (code >> 1) & 3 {
+                0 => "GDT",
+                2 => "LDT",
+                _ => "IDT",
+            };
+            print!(" {} selector {}", table, (code >> 3) & 0x1fff);
+            print_flags(code, &[(1 << 0, "external", "")]);
+        }
+        21 => print!(" control protection {}", code & 0x7fff),
+        _ => {}
+    }
+    println!();
+}
+
+/// Whether `addr` is mapped in the page tables at `cr3`, found by walking
+/// them rather than by touching `addr`. The page tables are identity mapped.
+fn is_mapped(cr3: u64, addr: u64) -> bool {
+    let mut table = cr3 & PTE_ADDR_MASK;
+    for level in (0..4).rev() {
+        let index = (addr >> (12 + 9 * level)) & 0x1ff;
+        let entry = unsafe { ((table + index * 8) as *const u64).read_volatile() };
+        if entry & PTE_PRESENT == 0 {
+            return false;
+        }
+        // 1G and 2M pages end the walk early
+        if (level == 1 || level == 2) && entry & PTE_HUGE != 0 {
+            return true;
+        }
+        table = entry & PTE_ADDR_MASK;
+    }
+    true
+}
+
+/// Prints the `CODE_BYTES` bytes at `rip`, `??` for those which can't be
+/// read. A page fault in here would start over at the top of the page fault
+/// stack, on top of this very report, so the page tables are asked first.
+/// `peek_u8` catches the rest, non canonical addresses for one.
+fn print_code(frame: &TrapFrame, regs: &ControlRegs) {
+    print!("code:");
+    for i in 0..CODE_BYTES {
+        let addr = frame.rip.wrapping_add(i);
+        let byte = if is_mapped(regs.cr3, addr) {
+            extable::peek_u8(addr).ok()
+        } else {
+            None
+        };
+        match byte {
+            Some(byte) => print!(" {:02x}", byte),
+            None => print!(" ??"),
+        }
+    }
+    println!();
+}
+
+/// The crash report for `frame`.
+fn report(frame: &TrapFrame, regs: &ControlRegs) {
+    let (mnemonic, name) = vector_name(frame.vector);
+    println!("EXCEPTION {} {} {}", frame.vector, mnemonic, name);
+    if has_error_code(frame.vector) {
+        print_error_code(frame.vector, frame.error_code);
+    }
+    println!(
+        "RIP {:#018x} CS {:#06x} RFLAGS {:#010x}",
+        frame.rip, frame.cs, frame.rflags
+    );
+    println!("RSP {:#018x} SS {:#06x}", frame.rsp, frame.ss);
+
+    let gprs = [
+        ("RAX", frame.rax),
+        ("RBX", frame.rbx),
+        ("RCX", frame.rcx),
+        ("RDX", frame.rdx),
+        ("RSI", frame.rsi),
+        ("RDI", frame.rdi),
+        ("RBP", frame.rbp),
+        ("R8 ", frame.r8),
+        ("R9 ", frame.r9),
+        ("R10", frame.r10),
+        ("R11", frame.r11),
+        ("R12", frame.r12),
+        ("R13", frame.r13),
+        ("R14", frame.r14),
+        ("R15", frame.r15),
+    ];
+    for row in gprs.chunks(3) {
+        for (name, value) in row {
+            print!("{} {:#018x}  ", name, value);
+        }
+        println!();
+    }
+    println!(
+        "CR0 {:#018x}  CR2 {:#018x}  CR3 {:#018x}",
+        regs.cr0, regs.cr2, regs.cr3
+    );
+    println!("CR4 {:#018x}  EFER {:#018x}", regs.cr4, regs.efer);
+    print_code(frame, regs);
+    backtrace::print(frame.rip, frame.rbp);
+}
@@ -1,4 +1,9 @@
+use core::ops::{Deref, DerefMut};
 use spin::{Mutex, MutexGuard};
+#[cfg(not(test))]
+use x86_64::instructions::interrupts;
+#[cfg(test)]
+use test_interrupts as interrupts;
 
 pub struct Locked<T> {
     inner: Mutex<T>,
@@ -16,6 +21,102 @@ impl<T> Locked<T> {
     }
 }
 
+/// A lock which keeps interrupts masked while it is held, so it can be taken
+/// from an interrupt handler without dead locking against the code the
+/// interrupt stopped in the middle of the same critical section.
+pub struct IrqLocked<T> {
+    inner: Mutex<T>,
+}
+
+impl<T> IrqLocked<T> {
+    pub const fn new(t: T) -> Self {
+        IrqLocked {
+            inner: Mutex::new(t),
+        }
+    }
+
+    pub fn lock(&self) -> IrqGuard<T> {
+        let enabled = interrupts::are_enabled();
+        if enabled {
+            interrupts::disable();
+        }
+        IrqGuard {
+            guard: Some(self.inner.lock()),
+            enabled,
+        }
+    }
+}
+
+pub struct IrqGuard<'a, T> {
+    guard: Option<MutexGuard<'a, T>>,
+    enabled: bool,
+}
+
+impl<'a, T> Deref for IrqGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        self.guard.as_ref().unwrap()
+    }
+}
+
+impl<'a, T> DerefMut for IrqGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        self.guard.as_mut().unwrap()
+    }
+}
+
+impl<'a, T> Drop for IrqGuard<'a, T> {
+    fn drop(&mut self) {
+        // release the spin lock before interrupts can come in again
+        self.guard.take();
+        if self.enabled {
+            interrupts::enable();
+        }
+    }
+}
+
+/// Host tests run in user mode, where cli faults. Each test thread gets an
+/// interrupt flag of its own instead, and `raise` stands in for an interrupt
+/// coming in: its handler runs right away while the flag is set, and as soon
+/// as it is set again otherwise.
+#[cfg(test)]
+pub mod test_interrupts {
+    use alloc::vec::Vec;
+    use core::cell::{Cell, RefCell};
+
+    std::thread_local! {
+        static ENABLED: Cell<bool> = Cell::new(true);
+        static PENDING: RefCell<Vec<fn()>> = RefCell::new(Vec::new());
+    }
+
+    pub fn are_enabled() -> bool {
+        ENABLED.with(|e| e.get())
+    }
+
+    pub fn disable() {
+        ENABLED.with(|e| e.set(false));
+    }
+
+    pub fn enable() {
+        ENABLED.with(|e| e.set(true));
+        while let Some(handler) = PENDING.with(|p| p.borrow_mut().pop()) {
+            raise(handler);
+        }
+    }
+
+    /// runs `handler` with interrupts masked, like the CPU does
+    pub fn raise(handler: fn()) {
+        if are_enabled() {
+            disable();
+            handler();
+            enable();
+        } else {
+            PENDING.with(|p| p.borrow_mut().push(handler));
+        }
+    }
+}
+
 pub struct Flag(bool);
 
 impl Flag {
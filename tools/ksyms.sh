@@ -0,0 +1,29 @@
+#!/bin/sh
+# Prints the function symbols of a linked kernel as NASM source for the
+# .ksyms section, which backtrace.rs searches to name stack frames:
+#   dq count
+#   dq addr, name offset, name length    ; count entries, sorted by addr
+#   db names...
+set -e
+
+nm -n -C --defined-only "$1" | awk '
+BEGIN { n = 0 }
+$2 ~ /^[tTwW]$/ {
+    name = $0
+    sub(/^[0-9a-fA-F]+ [a-zA-Z] /, "", name)
+    sub(/::h[0-9a-f]+$/, "", name)
+    gsub(/"/, "\047", name)
+    addr[n] = $1
+    names[n] = name
+    n++
+}
+END {
+    print "section .ksyms"
+    print "align 8"
+    printf "    dq %d\n", n
+    for (i = 0; i < n; i++)
+        printf "    dq 0x%s, n%d - names, n%d_end - n%d\n", addr[i], i, i, i
+    print "names:"
+    for (i = 0; i < n; i++)
+        printf "n%d: db \"%s\"\nn%d_end:\n", i, names[i], i
+}'